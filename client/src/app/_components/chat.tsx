@@ -62,14 +62,16 @@ export default function Chat({
         if (key) {
           console.log("flag1");
           const messages = [];
-          for (const message of response.data) {
-            message.content = decryptMessage(message.content, key);
+          for (const message of response.data.messages) {
+            if (!message.deleted) {
+              message.content = decryptMessage(message.content, key);
+            }
             messages.push(message);
           }
           setMessages(messages);
         } else {
           console.log("flag3");
-          setMessages(response.data);
+          setMessages(response.data.messages);
         }
       })
       .catch((error) => console.log(error))
@@ -86,7 +88,7 @@ export default function Chat({
         console.log("flag2");
         inputMessage = encryptMessage(inputMessage, keyRef.current);
       }
-      wsRef.current.send(inputMessage);
+      wsRef.current.send(JSON.stringify({ type: "send", content: inputMessage }));
       e.currentTarget.reset();
     }
   };
@@ -109,14 +111,36 @@ export default function Chat({
     };
 
     ws.onmessage = (event) => {
+      const data = JSON.parse(event.data);
+      if (data.type === "error") {
+        console.log("WebSocket error:", data.code, data.message);
+        return;
+      }
+      if (data.type === "message_deleted") {
+        setMessages((prev) =>
+          prev.map((m) =>
+            m.id === data.id
+              ? { ...m, content: "message deleted", deleted: true }
+              : m
+          )
+        );
+        return;
+      }
+      if (data.type !== "message") return;
+
+      // a replay after reconnecting can resend messages that are already shown
+      const appendMessage = (message: Message) =>
+        setMessages((prev) =>
+          prev.some((m) => m.id === message.id) ? prev : [...prev, message]
+        );
+
       if (keyRef.current) {
         console.log("flag4");
-        const message = JSON.parse(event.data);
-        message.content = decryptMessage(message.content, keyRef.current);
-        setMessages((prev) => [...prev, message]);
+        data.content = decryptMessage(data.content, keyRef.current);
+        appendMessage(data);
       } else {
         console.log("flag5");
-        setMessages((prev) => [...prev, JSON.parse(event.data)]);
+        appendMessage(data);
       }
     };
 
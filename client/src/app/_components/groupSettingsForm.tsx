@@ -8,7 +8,6 @@ import { ScrollArea } from "@/components/ui/scroll-area";
 import { FormEvent, useState } from "react";
 import type { Friend } from "../fetchData";
 import axios from "axios";
-import qs from "qs";
 
 interface Props {
   friends: Friend[];
@@ -73,17 +72,21 @@ export function GroupSettingsForm({
     }
     setIsLoading(true);
 
-    const pictureUrl = e.currentTarget.pictureUrl.value;
-    const payload = {
-      token: token,
-      groupId: groupId,
-      pictureUrl: pictureUrl,
-    };
+    const picture: File | undefined = e.currentTarget.picture.files?.[0];
+    if (!picture) {
+      setIsLoading(false);
+      return;
+    }
+    // the image itself is the body, the server sends back where it's served from
     axios
-      .put("https://api.gchat.cloud/group/edit-picture", qs.stringify(payload))
+      .put<{ picture_url: string }>(
+        `https://api.gchat.cloud/group/edit-picture?token=${token}&group_id=${groupId}`,
+        picture,
+        { headers: { "Content-Type": picture.type } }
+      )
       .then((response) => {
         if (response.status === 200) {
-          editGroupPicture(groupId, pictureUrl);
+          editGroupPicture(groupId, response.data.picture_url);
         }
       })
       .catch((error) => {
@@ -113,9 +116,9 @@ export function GroupSettingsForm({
       {pictureOpen && (
         <form onSubmit={(e) => editPicture(e)} className="flex flex-row gap-4">
           <Input
-            type="text"
-            name="pictureUrl"
-            placeholder="Picture URL"
+            type="file"
+            name="picture"
+            accept="image/png,image/jpeg,image/gif,image/webp"
             className="w-[70%]"
           />
           <Button className="w-[30%]" type="submit" disabled={isLoading}>
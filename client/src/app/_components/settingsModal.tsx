@@ -18,7 +18,11 @@ import { Button } from "@/components/ui/button";
 import { Input } from "@/components/ui/input";
 import { z } from "zod";
 import { useForm } from "@tanstack/react-form";
-import { generateProfilePictureSVG } from "../utils";
+import {
+  generateProfilePictureSVG,
+  svgToPng,
+  uploadProfilePicture,
+} from "../utils";
 import { useState } from "react";
 import { Label } from "@/components/ui/label";
 import { Tabs, TabsContent, TabsList, TabsTrigger } from "@/components/ui/tabs";
@@ -52,10 +56,22 @@ export default function SettingsModal({ user }: Props) {
       onChange: userSchema,
     },
     onSubmit: async (e) => {
+      const token = localStorage.getItem("token");
+      // a regenerated picture is still a local SVG, the server only keeps uploaded images
+      if (token && e.value.profilePicture !== user.profile_picture) {
+        try {
+          const picture = await svgToPng(e.value.profilePicture);
+          const pictureUrl = await uploadProfilePicture(token, picture);
+          setProfilePicture(pictureUrl);
+          form.setFieldValue("profilePicture", pictureUrl);
+        } catch (error) {
+          console.error("Failed to upload profile picture:", error);
+        }
+      }
+
       const payload = {
         email: e.value.email,
         username: e.value.username,
-        profilePicture: e.value.profilePicture,
         currentPassword: e.value.currentPassword,
         newPassword: e.value.newPassword,
       };
@@ -13,7 +13,11 @@ import { z } from "zod";
 import { useForm } from "@tanstack/react-form";
 import axios from "axios";
 import qs from "qs";
-import { generateProfilePictureSVG } from "../utils";
+import {
+  generateProfilePictureSVG,
+  svgToPng,
+  uploadProfilePicture,
+} from "../utils";
 
 const userSchema = z
   .object({
@@ -57,7 +61,6 @@ export default function SignUpModal({ showSignIn, successfullSignUp }: Props) {
           username: e.value.username,
           password: e.value.password,
           confirmPassword: e.value.confirmPassword,
-          profilePicture: generateProfilePictureSVG(e.value.username),
         };
         const payload = qs.stringify(data);
         const response = await axios.post(
@@ -66,6 +69,15 @@ export default function SignUpModal({ showSignIn, successfullSignUp }: Props) {
         );
         console.log("Registration successful");
         localStorage.setItem("token", response.data.token);
+        try {
+          const picture = await svgToPng(
+            generateProfilePictureSVG(e.value.username)
+          );
+          await uploadProfilePicture(response.data.token, picture);
+        } catch (error) {
+          // the account exists either way, the picture can be set again in settings
+          console.error("Failed to upload profile picture:", error);
+        }
         successfullSignUp();
       } catch (error) {
         if (axios.isAxiosError(error)) {
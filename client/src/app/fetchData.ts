@@ -46,6 +46,7 @@ export interface Message {
   content: string;
   timestamp: string;
   profile_picture: string;
+  deleted?: boolean;
 }
 
 export async function fetchAll() {
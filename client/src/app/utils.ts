@@ -1,4 +1,5 @@
 import crypto, { pbkdf2Sync } from "crypto";
+import axios from "axios";
 
 export function usernameToColor(username: string): string {
   let hash = 0;
@@ -53,6 +54,34 @@ export function generateProfilePictureSVG(username: string) {
   return `data:image/svg+xml;base64,${base64Svg}`;
 }
 
+// The server only takes PNG, JPEG, GIF or WebP pictures, so generated SVGs are drawn to a PNG first
+export function svgToPng(svgDataUrl: string, size = 256): Promise<Blob> {
+  return new Promise((resolve, reject) => {
+    const image = new Image();
+    image.onload = () => {
+      const canvas = document.createElement("canvas");
+      canvas.width = size;
+      canvas.height = size;
+      canvas.getContext("2d")?.drawImage(image, 0, 0, size, size);
+      canvas.toBlob((blob) =>
+        blob ? resolve(blob) : reject(new Error("Failed to draw picture"))
+      );
+    };
+    image.onerror = () => reject(new Error("Failed to load picture"));
+    image.src = svgDataUrl;
+  });
+}
+
+// Uploads the image bytes and returns the URL the server now serves the picture from
+export async function uploadProfilePicture(token: string, picture: Blob) {
+  const response = await axios.put<{ picture_url: string }>(
+    `https://api.gchat.cloud/user/edit-picture?token=${token}`,
+    picture,
+    { headers: { "Content-Type": picture.type } }
+  );
+  return response.data.picture_url;
+}
+
 export function getIdFromJWT() {
   const token = localStorage.getItem("token") ?? "";
   const base64Url = token.split(".")[1];
@@ -0,0 +1,3 @@
+ALTER TABLE messages ADD COLUMN reply_to INT REFERENCES messages(id) ON DELETE SET NULL;
+
+CREATE INDEX messages_reply_to_idx ON messages (reply_to);
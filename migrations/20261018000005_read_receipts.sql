@@ -0,0 +1,7 @@
+ALTER TABLE group_members ADD COLUMN last_read_message_id INT REFERENCES messages(id) ON DELETE SET NULL;
+
+-- existing members start caught up instead of with their whole history unread
+UPDATE group_members gm
+SET last_read_message_id = (SELECT MAX(m.id) FROM messages m WHERE m.group_id = gm.group_id);
+
+CREATE INDEX messages_group_id_id_idx ON messages (group_id, id);
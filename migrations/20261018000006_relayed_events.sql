@@ -0,0 +1,6 @@
+-- events too large for a NOTIFY payload (8000 bytes) are passed between instances through here
+CREATE TABLE relayed_events (
+  id BIGSERIAL PRIMARY KEY,
+  payload TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
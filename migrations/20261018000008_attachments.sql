@@ -0,0 +1,16 @@
+-- uploaded into a group and linked to a message once it's sent, until then only the uploader can see it
+CREATE TABLE attachments (
+    id SERIAL PRIMARY KEY,
+    storage_key TEXT NOT NULL UNIQUE,
+    group_id INT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+    uploader_id INT REFERENCES users(id) ON DELETE SET NULL,
+    message_id INT REFERENCES messages(id) ON DELETE CASCADE,
+    filename TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    -- hex encoded sha256 of the contents
+    checksum TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX attachments_message_id_idx ON attachments (message_id);
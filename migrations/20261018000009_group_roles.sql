@@ -0,0 +1,16 @@
+-- Group ownership lives here and nowhere else, the owner is the member with the 'owner' role.
+-- Only regular groups (group_type 1) have one, temp chat creators are still in temp_groups_info.user_id
+CREATE TYPE group_role AS ENUM ('member', 'admin', 'owner');
+
+ALTER TABLE group_members ADD COLUMN role group_role NOT NULL DEFAULT 'member';
+
+-- Groups created before this never recorded who made them, so an owner has to be picked or the group could
+-- never get an admin. The member with the oldest account (lowest user id) is picked, it's a guess but a stable
+-- one that every instance of the database agrees on. The owner can hand the group over with /group/transfer-ownership
+UPDATE group_members gm
+SET role = 'owner'
+FROM groups g
+WHERE g.id = gm.group_id AND g.group_type = 1
+AND gm.user_id = (SELECT MIN(user_id) FROM group_members WHERE group_id = g.id);
+
+CREATE UNIQUE INDEX group_members_owner_idx ON group_members (group_id) WHERE role = 'owner';
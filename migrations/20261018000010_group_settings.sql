@@ -0,0 +1,5 @@
+ALTER TABLE groups
+    ADD COLUMN description TEXT,
+    ADD COLUMN only_admins_can_post BOOLEAN NOT NULL DEFAULT FALSE,
+    -- admins were already the only ones allowed to add members
+    ADD COLUMN only_admins_can_add BOOLEAN NOT NULL DEFAULT TRUE;
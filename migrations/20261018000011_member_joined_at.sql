@@ -0,0 +1,2 @@
+-- decides who takes over when the owner leaves, members from before this don't have a real date
+ALTER TABLE group_members ADD COLUMN joined_at TIMESTAMPTZ NOT NULL DEFAULT now();
@@ -0,0 +1,17 @@
+CREATE TYPE message_kind AS ENUM (
+    'user',
+    'member_added',
+    'member_removed',
+    'member_left',
+    'group_renamed',
+    'description_changed',
+    'picture_changed',
+    'settings_changed',
+    'role_changed'
+);
+
+-- anything but 'user' is a system message describing a change to the group, user_id made the change
+-- and target_user_id is who it was made to
+ALTER TABLE messages
+    ADD COLUMN kind message_kind NOT NULL DEFAULT 'user',
+    ADD COLUMN target_user_id INT REFERENCES users(id) ON DELETE SET NULL;
@@ -0,0 +1,18 @@
+-- every running server instance, refreshed on a heartbeat so instances that died without cleaning up can be spotted
+CREATE TABLE server_instances (
+  id UUID PRIMARY KEY,
+  last_seen TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- declared from lowest to highest so MAX() picks a user's best status across instances
+CREATE TYPE presence_status AS ENUM ('offline', 'idle', 'online');
+
+-- each instance's view of the users connected to it, a user without a row on any live instance is offline
+CREATE TABLE instance_presence (
+  instance_id UUID NOT NULL REFERENCES server_instances(id) ON DELETE CASCADE,
+  user_id INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  status presence_status NOT NULL,
+  PRIMARY KEY (instance_id, user_id)
+);
+
+CREATE INDEX instance_presence_user_id_idx ON instance_presence (user_id);
@@ -0,0 +1,2 @@
+-- uploads that were never sent are removed after a day, this finds them without scanning every attachment
+CREATE INDEX attachments_unsent_created_at_idx ON attachments (created_at) WHERE message_id IS NULL;
@@ -0,0 +1,234 @@
+// Load test for the WebSocket broadcast path.
+//
+// Seeds `connections` users split into groups of `group_size`, opens one /ws socket per user against a
+// running server and has every socket send `events` typing frames to its group. Typing events are relayed
+// to every other member without touching the database, so the delivered events per second mostly measure
+// how fast the server can fan events out. Run it against two builds to compare them:
+//
+//     cargo run --release --bin loadtest -- [connections] [group_size] [events]
+//
+// Needs DATABASE_URL and JWT_KEY (the same as the server), LOADTEST_URL defaults to ws://localhost:3000.
+// The users and groups it seeds are removed again when the run finishes. It writes to whatever DATABASE_URL
+// points at, so it refuses to run unless LOADTEST_ALLOW_SEEDING=1 confirms that's a throwaway database.
+// Thousands of sockets need a raised open file limit (`ulimit -n`) on both sides, and a queue large
+// enough to absorb the bursts (e.g. WS_QUEUE_CAPACITY=100000) or evicted sockets will show up as missing events.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use dotenv::dotenv;
+use futures_util::{SinkExt, StreamExt};
+use gauth::models::Claims;
+use serde_json::{json, Value};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use tokio::sync::Barrier;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use uuid::Uuid;
+
+const CONNECT_CONCURRENCY: usize = 200;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[tokio::main]
+async fn main() {
+    dotenv().ok();
+
+    let mut args = std::env::args().skip(1).map(|arg| arg.parse::<usize>().expect("arguments must be numbers"));
+    let connections = args.next().unwrap_or(2000);
+    let group_size = args.next().unwrap_or(50).max(2);
+    // even, so every typing_start has a matching typing_stop
+    let events = args.next().unwrap_or(20).div_ceil(2) * 2;
+
+    if std::env::var("LOADTEST_ALLOW_SEEDING").as_deref() != Ok("1") {
+        eprintln!("Refusing to seed users into DATABASE_URL, set LOADTEST_ALLOW_SEEDING=1 if it's a test database");
+        std::process::exit(1);
+    }
+    let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let server_url = std::env::var("LOADTEST_URL").unwrap_or_else(|_| "ws://localhost:3000".to_string());
+
+    let pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect(&db_url)
+        .await
+        .expect("Failed to create pool");
+
+    println!("Seeding {} users in groups of {}", connections, group_size);
+    let user_ids = seed_users(connections, &pool).await;
+    let mut members = Vec::new();
+    for chunk in user_ids.chunks(group_size) {
+        let group_id = seed_group(chunk, &pool).await;
+        members.extend(chunk.iter().map(|user_id| (*user_id, group_id)));
+    }
+    let group_ids = members.iter().map(|(_, group_id)| *group_id).collect::<std::collections::HashSet<_>>();
+
+    // every event reaches every other member of the sender's group
+    let expected = user_ids
+        .chunks(group_size)
+        .map(|chunk| (chunk.len() * (chunk.len() - 1) * events) as u64)
+        .sum::<u64>();
+
+    let received = Arc::new(AtomicU64::new(0));
+    let closed = Arc::new(AtomicU64::new(0));
+    let start = Arc::new(Barrier::new(members.len() + 1));
+
+    println!("Connecting {} sockets to {}", members.len(), server_url);
+    let connect_started = Instant::now();
+    let mut tasks = Vec::new();
+    for batch in members.chunks(CONNECT_CONCURRENCY) {
+        let sockets = futures_util::future::join_all(batch.iter().map(|(user_id, _)| {
+            let token = mint_token(*user_id, &jwt_key);
+            connect(server_url.clone(), token)
+        }))
+        .await;
+
+        for ((_, group_id), socket) in batch.iter().zip(sockets) {
+            let (mut write, mut read) = socket.split();
+            let group_id = *group_id;
+
+            let received = received.clone();
+            let closed = closed.clone();
+            tokio::spawn(async move {
+                while let Some(Ok(message)) = read.next().await {
+                    match message {
+                        Message::Text(text) if text.contains("\"typing_start\"") || text.contains("\"typing_stop\"") => {
+                            received.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Message::Close(_) => break,
+                        _ => {}
+                    }
+                }
+                closed.fetch_add(1, Ordering::Relaxed);
+            });
+
+            let start = start.clone();
+            tasks.push(tokio::spawn(async move {
+                start.wait().await;
+                for i in 0..events {
+                    let kind = if i % 2 == 0 { "typing_start" } else { "typing_stop" };
+                    let frame = json!({ "v": 1, "type": kind, "group_id": group_id }).to_string();
+                    if write.send(Message::Text(frame)).await.is_err() {
+                        return None;
+                    }
+                }
+                Some(write)
+            }));
+        }
+    }
+    println!("Connected in {:.2?}", connect_started.elapsed());
+
+    let started = Instant::now();
+    start.wait().await;
+
+    let mut last_report = Instant::now();
+    while received.load(Ordering::Relaxed) < expected && started.elapsed() < DELIVERY_TIMEOUT {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if last_report.elapsed() > Duration::from_secs(1) {
+            println!("  {} / {} events delivered", received.load(Ordering::Relaxed), expected);
+            last_report = Instant::now();
+        }
+    }
+    let elapsed = started.elapsed();
+    let delivered = received.load(Ordering::Relaxed);
+
+    println!("Sent {} events, delivered {} of {} in {:.2?}", members.len() * events, delivered, expected, elapsed);
+    println!("Throughput: {:.0} events/s", delivered as f64 / elapsed.as_secs_f64());
+    println!("Sockets closed by the server: {}", closed.load(Ordering::Relaxed));
+
+    for task in tasks {
+        if let Ok(Some(mut write)) = task.await {
+            let _ = write.close().await;
+        }
+    }
+
+    sqlx::query!("DELETE FROM groups WHERE id = ANY($1)", &group_ids.into_iter().collect::<Vec<_>>())
+        .execute(&pool)
+        .await
+        .expect("Failed to remove load test groups");
+    sqlx::query!("DELETE FROM users WHERE id = ANY($1)", &user_ids)
+        .execute(&pool)
+        .await
+        .expect("Failed to remove load test users");
+}
+
+// named after the run so no existing account is ever reused, they have no password so nobody can log in as
+// them, and they're deleted again at the end
+async fn seed_users(count: usize, db: &PgPool) -> Vec<i32> {
+    let run = &Uuid::new_v4().simple().to_string()[..8];
+    let mut user_ids = Vec::with_capacity(count);
+    for i in 0..count {
+        let user_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (username, password)
+            VALUES ($1, '')
+            RETURNING id
+            "#,
+            format!("loadtest_{}_{}", run, i)
+        )
+        .fetch_one(db)
+        .await
+        .expect("Failed to create load test user");
+        user_ids.push(user_id);
+    }
+    user_ids
+}
+
+async fn seed_group(user_ids: &[i32], db: &PgPool) -> i32 {
+    let group_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO groups (name, group_type)
+        VALUES ('loadtest', 1)
+        RETURNING id
+        "#
+    )
+    .fetch_one(db)
+    .await
+    .expect("Failed to create load test group");
+
+    sqlx::query!(
+        r#"
+        INSERT INTO group_members (group_id, user_id)
+        SELECT $1, UNNEST($2::INT[])
+        "#,
+        group_id,
+        user_ids
+    )
+    .execute(db)
+    .await
+    .expect("Failed to add load test members");
+
+    group_id
+}
+
+fn mint_token(user_id: i32, jwt_key: &str) -> String {
+    let claims = Claims {
+        sub: user_id.to_string(),
+        exp: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() + 60 * 60,
+    };
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(jwt_key.as_bytes()),
+    )
+    .unwrap()
+}
+
+// waits for `ready` so every socket is subscribed before the first event goes out
+async fn connect(
+    server_url: String,
+    token: String,
+) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>> {
+    let url = url::Url::parse(&format!("{}/ws?token={}", server_url, token)).unwrap();
+    let (mut socket, _) = connect_async(url).await.expect("Failed to connect");
+
+    while let Some(Ok(message)) = socket.next().await {
+        if let Message::Text(text) = message {
+            let frame = serde_json::from_str::<Value>(&text).unwrap_or_default();
+            if frame["type"] == "ready" {
+                return socket;
+            }
+        }
+    }
+    panic!("Socket closed before it was ready");
+}
@@ -0,0 +1,205 @@
+use std::{collections::HashMap, sync::Arc};
+use axum::{body::Body, extract::{Query, State}, http::{header, HeaderMap, StatusCode}, response::IntoResponse, routing::{get, post}, Json, Router};
+use gauth::validate_token;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::state::ServerState;
+use crate::utils::queries::{fetch_attachment, insert_attachment, is_user_in_group};
+use crate::utils::types::{is_allowed_content_type, is_inline_content_type, NewAttachment};
+
+const MAX_FILENAME_LENGTH: usize = 255;
+
+pub fn router() -> Router<Arc<ServerState>> {
+    Router::new()
+        .route("/upload", post(upload_attachment))
+        .route("/get", get(get_attachment))
+}
+
+// The file is the raw request body, so clients don't need multipart forms
+async fn upload_attachment(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Body,
+) -> impl IntoResponse {
+    let token = match params.get("token") {
+        None => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Missing token" }))).into_response()
+        }
+        Some(token) => token,
+    };
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    let group_id = match params.get("group_id").map(|id| id.parse::<i32>()) {
+        Some(Ok(group_id)) => group_id,
+        _ => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Missing or invalid group id" }))).into_response()
+        }
+    };
+
+    if is_user_in_group(user_id, group_id, &state.db).await.is_err() {
+        return (StatusCode::FORBIDDEN, Json(json!({ "error": "Not a member of this group" }))).into_response();
+    }
+
+    // only the last path component, some browsers send the full path
+    let filename = params
+        .get("filename")
+        .and_then(|name| name.rsplit(['/', '\\']).next())
+        .map(str::trim)
+        .unwrap_or_default();
+    if filename.is_empty() || filename.chars().count() > MAX_FILENAME_LENGTH {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Missing or too long filename" }))).into_response();
+    }
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    if !is_allowed_content_type(&content_type) {
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, Json(json!({ "error": "File type not allowed" }))).into_response();
+    }
+
+    let max_size = state.message_limits.max_attachment_size;
+    let data = match axum::body::to_bytes(body, max_size).await {
+        Ok(data) if !data.is_empty() => data,
+        Ok(_) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "File is empty" }))).into_response()
+        }
+        Err(_) => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!({ "error": format!("File cannot be larger than {} bytes", max_size) })),
+            ).into_response()
+        }
+    };
+
+    let checksum = hex::encode(Sha256::digest(&data));
+    // optional, lets the client catch uploads corrupted on the way
+    if let Some(expected) = headers.get("x-checksum-sha256") {
+        if !expected.to_str().is_ok_and(|expected| expected.eq_ignore_ascii_case(&checksum)) {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Checksum mismatch" }))).into_response();
+        }
+    }
+
+    let storage_key = Uuid::new_v4().to_string();
+    if let Err(err) = state.storage.put(&storage_key, &data).await {
+        eprintln!("Failed to store attachment: {}", err);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Failed to store attachment" }))).into_response();
+    }
+
+    let attachment = NewAttachment {
+        storage_key: &storage_key,
+        group_id,
+        uploader_id: user_id,
+        filename,
+        content_type: &content_type,
+        size: data.len() as i64,
+        checksum: &checksum,
+    };
+    match insert_attachment(&attachment, &state.db).await {
+        Ok(id) => {
+            (StatusCode::OK, Json(json!({
+                "message": "Attachment Uploaded",
+                "id": id,
+                "filename": filename,
+                "content_type": content_type,
+                "size": attachment.size,
+                "checksum": checksum,
+            }))).into_response()
+        }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            if let Err(err) = state.storage.delete(&storage_key).await {
+                eprintln!("Failed to remove attachment {}: {}", storage_key, err);
+            }
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Failed to store attachment" }))).into_response()
+        }
+    }
+}
+
+async fn get_attachment(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let token = match params.get("token") {
+        None => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Missing token" }))).into_response()
+        }
+        Some(token) => token,
+    };
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    let attachment_id = match params.get("id").map(|id| id.parse::<i32>()) {
+        Some(Ok(attachment_id)) => attachment_id,
+        _ => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Missing or invalid attachment id" }))).into_response()
+        }
+    };
+
+    let attachment = match fetch_attachment(attachment_id, &state.db).await {
+        Ok(attachment) => attachment,
+        Err(sqlx::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "Attachment not found" }))).into_response()
+        }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Failed to fetch attachment" }))).into_response();
+        }
+    };
+
+    // unsent uploads are only visible to the uploader, sent ones to the group, and not at all once the message is deleted
+    let allowed = match attachment.message_id {
+        None => attachment.uploader_id == Some(user_id),
+        Some(_) => !attachment.message_deleted && is_user_in_group(user_id, attachment.group_id, &state.db).await.is_ok(),
+    };
+    if !allowed {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Attachment not found" }))).into_response();
+    }
+
+    let data = match state.storage.get(&attachment.storage_key).await {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Failed to read attachment {}: {}", attachment.id, err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Failed to read attachment" }))).into_response();
+        }
+    };
+    if hex::encode(Sha256::digest(&data)) != attachment.checksum {
+        eprintln!("Attachment {} doesn't match its checksum", attachment.id);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Failed to read attachment" }))).into_response();
+    }
+
+    let disposition = if is_inline_content_type(&attachment.content_type) { "inline" } else { "attachment" };
+    let filename = attachment
+        .filename
+        .chars()
+        .map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' { c } else { '_' })
+        .collect::<String>();
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, attachment.content_type),
+            (header::CONTENT_DISPOSITION, format!("{}; filename=\"{}\"", disposition, filename)),
+            (header::ETAG, format!("\"{}\"", attachment.checksum)),
+            (header::CACHE_CONTROL, "private, max-age=31536000, immutable".to_string()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        data,
+    ).into_response()
+}
@@ -3,10 +3,13 @@ use axum::{extract::{Query, State}, http::StatusCode, response::IntoResponse, ro
 use gauth::{validate_token, Auth};
 use serde_json::json;
 
-use crate::utils::{queries::{add_group_member, create_friendship, create_group, delete_friend_request, delete_friendship, delete_group, fetch_dm_id, fetch_friend_request}, types::{FriendForm, FriendRequestForm}};
+use crate::utils::{queries::{add_group_member, create_friendship, create_group, delete_friend_request, delete_friendship, delete_group, fetch_dm_id, fetch_friend_request}, types::{FriendForm, FriendRequestForm, GroupRole}};
 use crate::utils::queries::{fetch_friends_for_user, create_friend_request, fetch_incoming_requests, fetch_outgoing_requests};
 
+use crate::socket::broadcast_message;
 use crate::state::ServerState;
+use crate::storage::attachment::delete_files;
+use crate::utils::types::ServerEvent;
 
 
 
@@ -45,6 +48,7 @@ async fn get_friendships(
                 json!({
                     "friend_id": f.id,
                     "username": f.username,
+                    "profile_picture": f.profile_picture,
                 })
             }).collect::<Vec<_>>();
             (StatusCode::OK, Json(friendship_data)).into_response()
@@ -198,8 +202,8 @@ async fn accept_friend_request(
     let create_dm = create_group("DM".to_string(), 2, &state.db).await;
     match create_dm {
         Ok(group_id) => {
-            let member1 = add_group_member(user_id, group_id, &state.db).await;
-            let member2 = add_group_member(form.user_id, group_id, &state.db).await;
+            let member1 = add_group_member(user_id, group_id, GroupRole::Member, &state.db).await;
+            let member2 = add_group_member(form.user_id, group_id, GroupRole::Member, &state.db).await;
             match (member1, member2) {
                 (Ok(_), Ok(_)) => {},
                 _ => {
@@ -309,7 +313,10 @@ async fn remove_friendship(
     match fetch_dm_id(user_id, form.user_id, &state.db).await {
         Ok(dm_id) => {
             match delete_group(dm_id, &state.db).await {
-                Ok(_) => {},
+                Ok(attachment_keys) => {
+                    broadcast_message(state.clone(), dm_id, &ServerEvent::GroupDeleted { group_id: dm_id }).await;
+                    delete_files(state.storage.as_ref(), &attachment_keys).await;
+                },
                 Err(_) => {
                     return (
                         StatusCode::INTERNAL_SERVER_ERROR,
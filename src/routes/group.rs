@@ -1,11 +1,15 @@
 use std::{collections::HashMap, sync::Arc};
-use axum::{extract::{self, Query, State}, http::StatusCode, response::IntoResponse, routing::{get, post, put}, Form, Json, Router};
+use axum::{body::Body, extract::{self, Query, State}, http::StatusCode, response::{IntoResponse, Response}, routing::{get, post, put}, Form, Json, Router};
 use gauth::validate_token;
 use serde_json::json;
 
-use crate::{state::ServerState, utils::queries::{change_group_picture, fetch_friends_for_user, fetch_group_type, fetch_messages, is_user_in_group, remove_group_member}};
-use crate::utils::types::{CreateGroupForm, AddUsersForm, RemoveUserForm, EditPictureForm};
-use crate::utils::queries::{fetch_group_members, fetch_groups_for_user, add_group_member, create_group};
+use crate::{state::ServerState, utils::queries::{add_reaction, can_delete_message, change_group_picture, delete_group, delete_message, edit_message, fetch_friends_for_user, fetch_group_settings, fetch_group_type, fetch_member_role, fetch_message_ref, fetch_messages, fetch_reaction_count, fetch_thread, fetch_username, insert_system_message, is_user_in_group, leave_group, mark_read, remove_group_member, remove_reaction, set_member_role, transfer_group_ownership, update_group_settings}};
+use crate::socket::broadcast_message;
+use crate::routes::picture::{remove_replaced_picture, upload_picture};
+use crate::storage::attachment::delete_files;
+use crate::storage::picture::{delete_picture, picture_url};
+use crate::utils::types::{CreateGroupForm, AddUsersForm, RemoveUserForm, EditSettingsForm, LeaveGroupForm, SetRoleForm, TransferOwnershipForm, EditMessageForm, DeleteMessageForm, GroupRole, MarkReadForm, MessagePage, ReactionChange, ReactionForm, ReadReceipt, RemovedMember, RoleChange, ServerEvent, SystemMessage, check_group_description, check_emoji, check_group_name};
+use crate::utils::queries::{fetch_group_members, fetch_group_overviews_for_user, add_group_member, create_group};
 
 
 
@@ -16,8 +20,19 @@ pub fn router() -> Router<Arc<ServerState>> {
         .route("/get-users", get(get_users_in_group))
         .route("/add-users", post(add_users_to_group))
         .route("/remove-user", post(remove_user_from_group))
+        .route("/leave", post(leave_group_chat))
+        .route("/set-role", post(set_group_role))
+        .route("/transfer-ownership", post(transfer_ownership))
         .route("/edit-picture", put(edit_group_picture))
+        .route("/get-settings", get(get_group_settings))
+        .route("/edit-settings", put(edit_group_settings))
         .route("/get-messages", get(get_group_messages))
+        .route("/get-thread", get(get_message_thread))
+        .route("/edit-message", put(edit_group_message))
+        .route("/delete-message", post(delete_group_message))
+        .route("/add-reaction", post(add_message_reaction))
+        .route("/remove-reaction", post(remove_message_reaction))
+        .route("/mark-read", post(mark_group_read))
 }
 
 
@@ -37,7 +52,14 @@ async fn create_group_chat(
         }
     };
     
-    let group_id = match create_group(form.group_name, 1, &state.db).await {
+    let user_id = claims.sub.parse::<i32>().unwrap();
+
+    let group_name = match check_group_name(&form.group_name) {
+        Ok(group_name) => group_name,
+        Err(error) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))),
+    };
+
+    let group_id = match create_group(group_name, 1, &state.db).await {
         Ok(group_id) => group_id,
         Err(_) => {
             return (
@@ -47,12 +69,16 @@ async fn create_group_chat(
         }
     };
 
-    let mut member_ids = form.member_ids;
-    //add self to member ids array
-    member_ids.push(claims.sub.parse::<i32>().unwrap());
-    
-    for member_id in member_ids {
-        match add_group_member(member_id, group_id, &state.db).await {
+    // the creator owns the group
+    if add_group_member(user_id, group_id, GroupRole::Owner, &state.db).await.is_err() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"message": "Internal Server Error"})),
+        );
+    }
+
+    for member_id in form.member_ids.into_iter().filter(|id| *id != user_id) {
+        match add_group_member(member_id, group_id, GroupRole::Member, &state.db).await {
             Ok(_) => {}
             Err(_) => {
                 return (
@@ -84,14 +110,25 @@ async fn get_groups(
         }
     };
 
-    match fetch_groups_for_user(user_id, &state.db).await {
+    match fetch_group_overviews_for_user(user_id, &state.db).await {
         Ok(groups) => {
             let groups_data = groups.iter().map(|g| {
+                let last_message = g.last_message_id.map(|id| json!({
+                    "id": id,
+                    "user_id": g.last_message_user_id,
+                    "username": g.last_message_username,
+                    "preview": g.last_message_preview,
+                    "timestamp": g.last_message_timestamp.map(|t| t.to_rfc3339()),
+                    "deleted": g.last_message_deleted,
+                }));
                 json!({
                     "name": g.name,
                     "profile_picture": g.profile_picture,
                     "id": g.id,
                     "group_type": g.group_type,
+                    "unread_count": g.unread_count,
+                    "last_read_message_id": g.last_read_message_id,
+                    "last_message": last_message,
                 })
             }).collect::<Vec<_>>();
             (StatusCode::OK, Json(groups_data)).into_response()
@@ -133,6 +170,7 @@ async fn get_users_in_group(
                     "username": u.username,
                     "profile_picture": u.profile_picture,
                     "friend_id": u.id,
+                    "role": u.role,
                 })
             }).collect::<Vec<_>>();
 
@@ -168,12 +206,19 @@ async fn add_users_to_group(
         }
     };
 
-    match is_user_in_group(user_id, form.group_id, &state.db).await {
-        Ok(_) => {}
+    let required = match fetch_group_settings(form.group_id, &state.db).await {
+        Ok(settings) if settings.only_admins_can_add => GroupRole::Admin,
+        Ok(_) => GroupRole::Member,
+        Err(sqlx::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "Group not found" }))).into_response()
+        }
         Err(_) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR,Json(json!({ "error": "Unauthorized" }))).into_response()
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response();
         }
     };
+    if let Err(response) = require_role(user_id, form.group_id, required, &state).await {
+        return response;
+    }
 
     match fetch_group_type(form.group_id, &state.db).await {
         Ok(group_type) => {
@@ -195,8 +240,10 @@ async fn add_users_to_group(
     }
 
     for member_id in form.new_member_ids {
-        match add_group_member(member_id, form.group_id, &state.db).await {
-            Ok(_) => {}
+        match add_group_member(member_id, form.group_id, GroupRole::Member, &state.db).await {
+            Ok(_) => {
+                post_system_message(&state, form.group_id, user_id, SystemMessage::MemberAdded { target_id: member_id }).await;
+            }
             Err(_) => {
                 return (
                     StatusCode::INTERNAL_SERVER_ERROR,
@@ -222,11 +269,9 @@ async fn remove_user_from_group(
         }
     };
 
-    match is_user_in_group(user_id, form.group_id, &state.db).await {
-        Ok(_) => {}
-        Err(_) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR,Json(json!({ "error": "Unauthorized" }))).into_response()
-        }
+    let role = match require_role(user_id, form.group_id, GroupRole::Admin, &state).await {
+        Ok(role) => role,
+        Err(response) => return response,
     };
 
     match fetch_group_type(form.group_id, &state.db).await {
@@ -240,8 +285,26 @@ async fn remove_user_from_group(
         }
     };
 
+    // admins can only remove members, the owner can also remove admins
+    match fetch_member_role(form.remove_id, form.group_id, &state.db).await {
+        Ok(Some(removed_role)) if removed_role < role => {}
+        Ok(Some(_)) => {
+            return (StatusCode::FORBIDDEN, Json(json!({ "error": "Can only remove members with a lower role" }))).into_response()
+        }
+        Ok(None) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "User not in group" }))).into_response()
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response();
+        }
+    }
+
     match remove_group_member(form.remove_id, form.group_id, &state.db).await {
         Ok(_) => {
+            // also cuts off the removed user's open sockets, see socket::fanout::deliver
+            let removed = RemovedMember { group_id: form.group_id, user_id: form.remove_id };
+            broadcast_message(state.clone(), form.group_id, &ServerEvent::MemberRemoved(removed)).await;
+            post_system_message(&state, form.group_id, user_id, SystemMessage::MemberRemoved { target_id: form.remove_id }).await;
             return (StatusCode::OK, Json(json!({"message": "User Removed"}))).into_response();
         }
         Err(_) => {
@@ -252,9 +315,9 @@ async fn remove_user_from_group(
     }
 }
 
-async fn edit_group_picture(
+async fn leave_group_chat(
     State(state): State<Arc<ServerState>>,
-    Form(form): Form<EditPictureForm>,
+    extract::Json(form): extract::Json<LeaveGroupForm>,
 ) -> impl IntoResponse {
     let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
     let user_id = match validate_token(&form.token, jwt_key).await {
@@ -264,21 +327,293 @@ async fn edit_group_picture(
         }
     };
 
-    match is_user_in_group(user_id, form.group_id, &state.db).await {
-        Ok(_) => {}
+    // DMs end by removing the friend, temp chats by expiring
+    match fetch_group_type(form.group_id, &state.db).await {
+        Ok(1) => {}
+        Ok(_) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Only group chats can be left" }))).into_response()
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "Group not found" }))).into_response()
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response();
+        }
+    }
+
+    let leave = match leave_group(user_id, form.group_id, &state.db).await {
+        Ok(leave) => leave,
+        Err(sqlx::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "User not in group" }))).into_response()
+        }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"message": "Failed to leave group"}))).into_response();
+        }
+    };
+
+    // the last one out deletes the group
+    if leave.remaining == 0 {
+        match delete_group(form.group_id, &state.db).await {
+            Ok(attachment_keys) => {
+                broadcast_message(state.clone(), form.group_id, &ServerEvent::GroupDeleted { group_id: form.group_id }).await;
+                delete_files(state.storage.as_ref(), &attachment_keys).await;
+            }
+            Err(err) => eprintln!("Failed to delete empty group {}: {}", form.group_id, err),
+        }
+        return (StatusCode::OK, Json(json!({"message": "Left Group", "group_deleted": true}))).into_response();
+    }
+
+    let removed = RemovedMember { group_id: form.group_id, user_id };
+    broadcast_message(state.clone(), form.group_id, &ServerEvent::MemberRemoved(removed)).await;
+    post_system_message(&state, form.group_id, user_id, SystemMessage::MemberLeft).await;
+    if let Some(new_owner_id) = leave.new_owner_id {
+        let change = RoleChange { group_id: form.group_id, user_id: new_owner_id, role: GroupRole::Owner };
+        broadcast_message(state.clone(), form.group_id, &ServerEvent::RoleChanged(change)).await;
+    }
+
+    (StatusCode::OK, Json(json!({"message": "Left Group", "group_deleted": false}))).into_response()
+}
+
+async fn set_group_role(
+    State(state): State<Arc<ServerState>>,
+    extract::Json(form): extract::Json<SetRoleForm>,
+) -> impl IntoResponse {
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(&form.token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
         Err(_) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR,Json(json!({ "error": "Unauthorized" }))).into_response()
+            return (StatusCode::UNAUTHORIZED,Json(json!({ "error": "Invalid token" }))).into_response()
         }
     };
 
-    match change_group_picture(form.group_id, form.picture_url, &state.db).await {
+    if form.role == GroupRole::Owner {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Use /group/transfer-ownership to change the owner" }))).into_response();
+    }
+    if let Err(response) = require_role(user_id, form.group_id, GroupRole::Owner, &state).await {
+        return response;
+    }
+
+    if form.user_id == user_id {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "The owner's role can only change by transferring ownership" }))).into_response();
+    }
+
+    match set_member_role(form.user_id, form.group_id, form.role, &state.db).await {
         Ok(_) => {
-            return (StatusCode::OK, Json(json!({"message": "Picture Updated"}))).into_response();
+            let change = RoleChange { group_id: form.group_id, user_id: form.user_id, role: form.role };
+            broadcast_message(state.clone(), form.group_id, &ServerEvent::RoleChanged(change)).await;
+            post_system_message(&state, form.group_id, user_id, SystemMessage::RoleChanged { target_id: form.user_id, role: form.role }).await;
+
+            (StatusCode::OK, Json(json!({"message": "Role Updated"}))).into_response()
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            (StatusCode::NOT_FOUND, Json(json!({ "error": "User not in group" }))).into_response()
         }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"message": "Failed to update role"}))).into_response()
+        }
+    }
+}
+
+async fn transfer_ownership(
+    State(state): State<Arc<ServerState>>,
+    extract::Json(form): extract::Json<TransferOwnershipForm>,
+) -> impl IntoResponse {
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(&form.token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
         Err(_) => {
-            return (
+            return (StatusCode::UNAUTHORIZED,Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    if let Err(response) = require_role(user_id, form.group_id, GroupRole::Owner, &state).await {
+        return response;
+    }
+
+    if form.user_id == user_id {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Already the owner" }))).into_response();
+    }
+
+    match transfer_group_ownership(user_id, form.user_id, form.group_id, &state.db).await {
+        Ok(_) => {
+            for (changed_id, role) in [(user_id, GroupRole::Admin), (form.user_id, GroupRole::Owner)] {
+                let change = RoleChange { group_id: form.group_id, user_id: changed_id, role };
+                broadcast_message(state.clone(), form.group_id, &ServerEvent::RoleChanged(change)).await;
+            }
+            let message = SystemMessage::RoleChanged { target_id: form.user_id, role: GroupRole::Owner };
+            post_system_message(&state, form.group_id, user_id, message).await;
+
+            (StatusCode::OK, Json(json!({"message": "Ownership Transferred"}))).into_response()
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            (StatusCode::NOT_FOUND, Json(json!({ "error": "User not in group" }))).into_response()
+        }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"message": "Failed to transfer ownership"}))).into_response()
+        }
+    }
+}
+
+async fn get_group_settings(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let token = match params.get("token") {
+        None => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Missing token" }))).into_response()
+        }
+        Some(token) => token,
+    };
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED,Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    let group_id = match params.get("group_id").map(|id| id.parse::<i32>()) {
+        Some(Ok(group_id)) => group_id,
+        _ => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Missing or invalid group id" }))).into_response()
+        }
+    };
+
+    if let Err(response) = require_role(user_id, group_id, GroupRole::Member, &state).await {
+        return response;
+    }
+
+    match fetch_group_settings(group_id, &state.db).await {
+        Ok(settings) => (StatusCode::OK, Json(settings)).into_response(),
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Failed to fetch settings" }))).into_response()
+        }
+    }
+}
+
+async fn edit_group_settings(
+    State(state): State<Arc<ServerState>>,
+    extract::Json(form): extract::Json<EditSettingsForm>,
+) -> impl IntoResponse {
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(&form.token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED,Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    let name = match form.name.as_deref().map(check_group_name).transpose() {
+        Ok(name) => name,
+        Err(error) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response(),
+    };
+    let description = match form.description.as_deref().map(check_group_description).transpose() {
+        Ok(description) => description,
+        Err(error) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response(),
+    };
+    if name.is_none() && description.is_none() && form.only_admins_can_post.is_none() && form.only_admins_can_add.is_none() {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Nothing to change" }))).into_response();
+    }
+
+    if let Err(response) = require_role(user_id, form.group_id, GroupRole::Admin, &state).await {
+        return response;
+    }
+
+    // kept to only announce what actually changed
+    let old_settings = match fetch_group_settings(form.group_id, &state.db).await {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response();
+        }
+    };
+
+    match update_group_settings(form.group_id, name, description, form.only_admins_can_post, form.only_admins_can_add, &state.db).await {
+        Ok(settings) => {
+            let response = Json(json!({"message": "Settings Updated", "settings": &settings}));
+            if settings.name != old_settings.name {
+                post_system_message(&state, form.group_id, user_id, SystemMessage::GroupRenamed { name: &settings.name }).await;
+            }
+            if settings.description != old_settings.description {
+                post_system_message(&state, form.group_id, user_id, SystemMessage::DescriptionChanged).await;
+            }
+            if settings.only_admins_can_post != old_settings.only_admins_can_post
+                || settings.only_admins_can_add != old_settings.only_admins_can_add
+            {
+                post_system_message(&state, form.group_id, user_id, SystemMessage::SettingsChanged).await;
+            }
+            broadcast_message(state.clone(), form.group_id, &ServerEvent::GroupUpdated { updated_by: user_id, settings }).await;
+
+            (StatusCode::OK, response).into_response()
+        }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"message": "Failed to update settings"}))).into_response()
+        }
+    }
+}
+
+// The image is the raw request body, see routes::picture
+async fn edit_group_picture(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+    body: Body,
+) -> impl IntoResponse {
+    let token = match params.get("token") {
+        None => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Missing token" }))).into_response()
+        }
+        Some(token) => token,
+    };
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED,Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    let group_id = match params.get("group_id").map(|id| id.parse::<i32>()) {
+        Some(Ok(group_id)) => group_id,
+        _ => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Missing or invalid group id" }))).into_response()
+        }
+    };
+
+    if let Err(response) = require_role(user_id, group_id, GroupRole::Admin, &state).await {
+        return response;
+    }
+
+    let picture_id = match upload_picture(&state, body).await {
+        Ok(picture_id) => picture_id,
+        Err(response) => return response,
+    };
+
+    let url = picture_url(picture_id);
+    match change_group_picture(group_id, url.clone(), &state.db).await {
+        Ok(old_url) => {
+            remove_replaced_picture(&state, old_url).await;
+            post_system_message(&state, group_id, user_id, SystemMessage::PictureChanged).await;
+            (StatusCode::OK, Json(json!({"message": "Picture Updated", "picture_url": url}))).into_response()
+        }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            delete_picture(&state.storage, picture_id).await;
+            (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"message": "Failed to update picture"}))).into_response();
+                Json(json!({"message": "Failed to update picture"}))).into_response()
         }
     }
 }
@@ -332,20 +667,19 @@ async fn get_group_messages(
         }
     }
 
-    match fetch_messages(group_id, &state.db).await {
-        Ok(messages) => {
-            let message_data = messages.iter().map(|m| {
-                json!({
-                    "id": m.id,
-                    "content": m.content,
-                    "user_id": m.user_id,
-                    "username": m.username,
-                    "timestamp": m.timestamp.to_rfc3339(),
-                    "profile_picture": m.profile_picture
-                })
-            }).collect::<Vec<_>>();
-            
-            (StatusCode::OK, Json(message_data)).into_response()
+    let page = match MessagePage::from_params(&params) {
+        Ok(page) => page,
+        Err(error) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response();
+        }
+    };
+
+    match fetch_messages(group_id, Some(user_id), &page, &state.db).await {
+        Ok(history) => {
+            (StatusCode::OK, Json(json!({
+                "messages": history.messages,
+                "next_cursor": history.next_cursor,
+            }))).into_response()
         },
         Err(err) => {
             eprintln!("Database error: {}", err);
@@ -356,3 +690,331 @@ async fn get_group_messages(
         }
     }
 }
+
+async fn get_message_thread(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let token = match params.get("token") {
+        None => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Missing token" }))).into_response()
+        }
+        Some(token) => token,
+    };
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED,Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+    let message_id = match params.get("message_id").map(|id| id.parse::<i32>()) {
+        Some(Ok(message_id)) => message_id,
+        _ => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Missing message_id" }))).into_response()
+        }
+    };
+
+    let message = match fetch_message_ref(message_id, &state.db).await {
+        Ok(message) => message,
+        Err(sqlx::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "Message not found" }))).into_response()
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response();
+        }
+    };
+
+    if is_user_in_group(user_id, message.group_id, &state.db).await.is_err() {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "User not in group" }))).into_response()
+    }
+
+    match fetch_thread(message_id, user_id, &state.db).await {
+        Ok(messages) => {
+            (StatusCode::OK, Json(json!({ "messages": messages }))).into_response()
+        }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to fetch thread" })),
+            ).into_response()
+        }
+    }
+}
+
+async fn edit_group_message(
+    State(state): State<Arc<ServerState>>,
+    extract::Json(form): extract::Json<EditMessageForm>,
+) -> impl IntoResponse {
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(&form.token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED,Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    let content = match state.message_limits.check_content(&form.content, false) {
+        Ok(content) => content,
+        Err(error) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response(),
+    };
+
+    let message = match fetch_message_ref(form.message_id, &state.db).await {
+        Ok(message) if !message.deleted => message,
+        Ok(_) | Err(sqlx::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "Message not found" }))).into_response()
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response();
+        }
+    };
+
+    if is_user_in_group(user_id, message.group_id, &state.db).await.is_err() {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "User not in group" }))).into_response()
+    }
+
+    if message.system {
+        return (StatusCode::FORBIDDEN, Json(json!({ "error": "System messages cannot be edited" }))).into_response();
+    }
+    if message.user_id != user_id {
+        return (StatusCode::FORBIDDEN, Json(json!({ "error": "Only the author can edit a message" }))).into_response();
+    }
+
+    if let Err(limited) = state.rate_limiter.check(user_id, message.group_id) {
+        let retry_after_ms = limited.retry_after.as_millis() as u64;
+        return (StatusCode::TOO_MANY_REQUESTS, Json(json!({ "error": "Too many messages", "retry_after_ms": retry_after_ms }))).into_response();
+    }
+
+    match edit_message(form.message_id, content, &state.db).await {
+        Ok(edit) => {
+            let response = json!({"message": "Message Edited", "id": edit.id, "edited_at": edit.edited_at.to_rfc3339()});
+            broadcast_message(state.clone(), edit.group_id, &ServerEvent::MessageEdited(edit)).await;
+
+            (StatusCode::OK, Json(response)).into_response()
+        }
+        Err(sqlx::Error::RowNotFound) => (StatusCode::NOT_FOUND, Json(json!({ "error": "Message not found" }))).into_response(),
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"message": "Failed to edit message"}))).into_response()
+        }
+    }
+}
+
+async fn delete_group_message(
+    State(state): State<Arc<ServerState>>,
+    extract::Json(form): extract::Json<DeleteMessageForm>,
+) -> impl IntoResponse {
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(&form.token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED,Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    let message = match fetch_message_ref(form.message_id, &state.db).await {
+        Ok(message) if !message.deleted => message,
+        Ok(_) | Err(sqlx::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "Message not found" }))).into_response()
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response();
+        }
+    };
+
+    if is_user_in_group(user_id, message.group_id, &state.db).await.is_err() {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "User not in group" }))).into_response()
+    }
+
+    if message.system {
+        return (StatusCode::FORBIDDEN, Json(json!({ "error": "System messages cannot be deleted" }))).into_response();
+    }
+
+    match can_delete_message(user_id, &message, &state.db).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (StatusCode::FORBIDDEN, Json(json!({ "error": "Only the author or the group owner can delete a message" }))).into_response();
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response();
+        }
+    }
+
+    match delete_message(form.message_id, &state.db).await {
+        Ok(deletion) => {
+            delete_files(state.storage.as_ref(), &deletion.attachment_keys).await;
+            broadcast_message(state.clone(), deletion.group_id, &ServerEvent::MessageDeleted(deletion)).await;
+
+            (StatusCode::OK, Json(json!({"message": "Message Deleted"}))).into_response()
+        }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"message": "Failed to delete message"}))).into_response()
+        }
+    }
+}
+
+async fn add_message_reaction(
+    State(state): State<Arc<ServerState>>,
+    extract::Json(form): extract::Json<ReactionForm>,
+) -> impl IntoResponse {
+    change_reaction(state, form, true).await
+}
+
+async fn remove_message_reaction(
+    State(state): State<Arc<ServerState>>,
+    extract::Json(form): extract::Json<ReactionForm>,
+) -> impl IntoResponse {
+    change_reaction(state, form, false).await
+}
+
+async fn change_reaction(state: Arc<ServerState>, form: ReactionForm, added: bool) -> Response {
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(&form.token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED,Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    let emoji = match check_emoji(&form.emoji) {
+        Ok(emoji) => emoji,
+        Err(error) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response(),
+    };
+
+    let message = match fetch_message_ref(form.message_id, &state.db).await {
+        Ok(message) if !message.deleted => message,
+        Ok(_) | Err(sqlx::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "Message not found" }))).into_response()
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response();
+        }
+    };
+
+    if is_user_in_group(user_id, message.group_id, &state.db).await.is_err() {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "User not in group" }))).into_response()
+    }
+
+    let result = if added {
+        add_reaction(form.message_id, user_id, emoji, &state.db).await
+    } else {
+        remove_reaction(form.message_id, user_id, emoji, &state.db).await
+    };
+
+    let count = match result {
+        Ok(_) => fetch_reaction_count(form.message_id, emoji, &state.db).await,
+        Err(e) => Err(e),
+    };
+    let count = match count {
+        Ok(count) => count,
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"message": "Failed to update reaction"}))).into_response();
+        }
+    };
+
+    let change = ReactionChange {
+        message_id: form.message_id,
+        group_id: message.group_id,
+        user_id,
+        emoji: emoji.to_string(),
+        count,
+    };
+    let event = if added { ServerEvent::ReactionAdded(change) } else { ServerEvent::ReactionRemoved(change) };
+    broadcast_message(state.clone(), message.group_id, &event).await;
+
+    (StatusCode::OK, Json(json!({"message": "Reaction Updated", "count": count}))).into_response()
+}
+
+async fn mark_group_read(
+    State(state): State<Arc<ServerState>>,
+    extract::Json(form): extract::Json<MarkReadForm>,
+) -> impl IntoResponse {
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(&form.token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED,Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    if is_user_in_group(user_id, form.group_id, &state.db).await.is_err() {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Unauthorized" }))).into_response()
+    }
+
+    match fetch_message_ref(form.message_id, &state.db).await {
+        Ok(message) if message.group_id == form.group_id => {}
+        Ok(_) | Err(sqlx::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "Message not found" }))).into_response()
+        }
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response();
+        }
+    }
+
+    match mark_read(user_id, form.group_id, form.message_id, &state.db).await {
+        Ok(Some(message_id)) => {
+            let receipt = ReadReceipt { group_id: form.group_id, user_id, message_id };
+            broadcast_message(state.clone(), form.group_id, &ServerEvent::ReadReceipt(receipt)).await;
+
+            (StatusCode::OK, Json(json!({"message": "Marked as read"}))).into_response()
+        }
+        // already read further than this message
+        Ok(None) => (StatusCode::OK, Json(json!({"message": "Marked as read"}))).into_response(),
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"message": "Failed to mark as read"}))).into_response()
+        }
+    }
+}
+
+// The caller's role in the group, or the response to send back when it's below `required`
+async fn require_role(user_id: i32, group_id: i32, required: GroupRole, state: &Arc<ServerState>) -> Result<GroupRole, Response> {
+    match fetch_member_role(user_id, group_id, &state.db).await {
+        Ok(Some(role)) if role >= required => Ok(role),
+        Ok(Some(_)) => {
+            let error = match required {
+                GroupRole::Owner => "Only the group owner can do this",
+                _ => "Only group admins can do this",
+            };
+            Err((StatusCode::FORBIDDEN, Json(json!({ "error": error }))).into_response())
+        }
+        Ok(None) => Err((StatusCode::FORBIDDEN, Json(json!({ "error": "Not a member of this group" }))).into_response()),
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"message": "Internal Server Error"}))).into_response())
+        }
+    }
+}
+
+// Stores the change as a message in the group and pushes it to the members. The change itself
+// already went through, so failing here is only logged
+async fn post_system_message(state: &Arc<ServerState>, group_id: i32, actor_id: i32, message: SystemMessage<'_>) {
+    let actor = match fetch_username(actor_id, &state.db).await {
+        Ok(actor) => actor,
+        Err(err) => return eprintln!("Failed to post system message in group {}: {}", group_id, err),
+    };
+    let target = match message.target_id() {
+        Some(target_id) => match fetch_username(target_id, &state.db).await {
+            Ok(target) => target,
+            Err(err) => return eprintln!("Failed to post system message in group {}: {}", group_id, err),
+        },
+        None => String::new(),
+    };
+
+    let content = message.describe(&actor, &target);
+    match insert_system_message(group_id, actor_id, message.kind(), message.target_id(), content, &state.db).await {
+        Ok(record) => broadcast_message(state.clone(), group_id, &ServerEvent::Message(record)).await,
+        Err(err) => eprintln!("Failed to post system message in group {}: {}", group_id, err),
+    }
+}
@@ -6,6 +6,9 @@ pub mod user;
 pub mod friend;
 pub mod group;
 pub mod temp_group;
+pub mod search;
+pub mod attachment;
+pub mod picture;
 
 pub fn app_routes() -> Router<Arc<ServerState>> {
     Router::new()
@@ -13,4 +16,7 @@ pub fn app_routes() -> Router<Arc<ServerState>> {
     .nest("/group", group::router())
     .nest("/user", user::router())
     .nest("/temp-group", temp_group::router())
+    .nest("/search", search::router())
+    .nest("/attachment", attachment::router())
+    .nest("/picture", picture::router())
 }
\ No newline at end of file
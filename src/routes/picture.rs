@@ -0,0 +1,90 @@
+use std::{collections::HashMap, sync::Arc};
+use axum::{body::Body, extract::{Query, State}, http::{header, StatusCode}, response::{IntoResponse, Response}, routing::get, Json, Router};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::state::ServerState;
+use crate::storage::picture::{delete_picture, picture_id_from_url, picture_key, store_picture, PictureError, MAX_PICTURE_SIZE, PICTURE_SIZES};
+
+pub fn router() -> Router<Arc<ServerState>> {
+    Router::new()
+        .route("/get", get(get_picture))
+}
+
+// Public so pictures work in <img> tags, the ids are random and only handed out to people who can see them
+async fn get_picture(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let picture_id = match params.get("id").map(|id| Uuid::parse_str(id)) {
+        Some(Ok(picture_id)) => picture_id,
+        _ => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Missing or invalid picture id" }))).into_response()
+        }
+    };
+
+    let size = match params.get("size").map(|size| size.parse::<u32>()) {
+        None => PICTURE_SIZES[0],
+        Some(Ok(size)) if PICTURE_SIZES.contains(&size) => size,
+        Some(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": format!("Size must be one of {:?}", PICTURE_SIZES) })),
+            ).into_response()
+        }
+    };
+
+    let data = match state.storage.get(&picture_key(picture_id, size)).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "Picture not found" }))).into_response()
+        }
+        Err(err) => {
+            eprintln!("Failed to read picture {}: {}", picture_id, err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Failed to read picture" }))).into_response();
+        }
+    };
+
+    // a new upload always gets a new id, so a picture never changes
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        data,
+    ).into_response()
+}
+
+// Reads an uploaded picture from the request body and stores its thumbnails
+pub async fn upload_picture(state: &Arc<ServerState>, body: Body) -> Result<Uuid, Response> {
+    let data = match axum::body::to_bytes(body, MAX_PICTURE_SIZE).await {
+        Ok(data) => data,
+        Err(_) => {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!({ "error": format!("Picture cannot be larger than {} bytes", MAX_PICTURE_SIZE) })),
+            ).into_response())
+        }
+    };
+
+    match store_picture(&state.storage, data.to_vec()).await {
+        Ok(picture_id) => Ok(picture_id),
+        Err(PictureError::NotAnImage) => Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(json!({ "error": "Picture must be a PNG, JPEG, GIF or WebP image" })),
+        ).into_response()),
+        Err(PictureError::Storage(err)) => {
+            eprintln!("Failed to store picture: {}", err);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Failed to store picture" }))).into_response())
+        }
+    }
+}
+
+// removes the picture a new upload replaced, URLs saved before uploads existed are left alone
+pub async fn remove_replaced_picture(state: &Arc<ServerState>, old_url: Option<String>) {
+    if let Some(picture_id) = old_url.as_deref().and_then(picture_id_from_url) {
+        delete_picture(&state.storage, picture_id).await;
+    }
+}
@@ -0,0 +1,96 @@
+use std::{collections::HashMap, sync::Arc};
+use axum::{extract::{Query, State}, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use bcrypt::verify;
+use gauth::validate_token;
+use serde_json::json;
+
+use crate::routes::temp_group::check_end_date;
+use crate::state::ServerState;
+use crate::utils::queries::{fetch_temp_chat, search_messages};
+use crate::utils::types::SearchQuery;
+
+pub fn router() -> Router<Arc<ServerState>> {
+    Router::new()
+        .route("/", get(search))
+}
+
+async fn search(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let query = match SearchQuery::from_params(&params) {
+        Ok(query) => query,
+        Err(error) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response();
+        }
+    };
+
+    let user_id = match params.get("token") {
+        Some(token) => {
+            let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+            match validate_token(token, jwt_key).await {
+                Ok(claims) => Some(claims.sub.parse::<i32>().unwrap()),
+                Err(_) => {
+                    return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Invalid token" }))).into_response()
+                }
+            }
+        }
+        None => None,
+    };
+
+    // temp chats are only searched with the same key and password /temp-group/get-messages takes
+    let temp_group_id = match params.get("temp") {
+        Some(temp_chat_key) => {
+            let temp_chat_info = match fetch_temp_chat(temp_chat_key.clone(), &state.db).await {
+                Ok(temp_chat_info) => temp_chat_info,
+                Err(sqlx::Error::RowNotFound) => {
+                    return (StatusCode::NOT_FOUND, Json(json!({ "error": "Chat not found" }))).into_response()
+                }
+                Err(err) => {
+                    eprintln!("Database error: {}", err);
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({ "error": "Failed to fetch chat info" })),
+                    ).into_response();
+                }
+            };
+
+            if check_end_date(temp_chat_info.end_date, temp_chat_info.group_id, &state).await.is_err() {
+                return (StatusCode::NOT_FOUND, Json(json!({ "error": "Chat not found" }))).into_response();
+            }
+
+            let password = params.get("password").map(String::as_str).unwrap_or_default();
+            if !(verify(password, &temp_chat_info.password).unwrap_or(false)) {
+                return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Unauthorized" }))).into_response();
+            }
+
+            Some(temp_chat_info.group_id)
+        }
+        None => None,
+    };
+
+    if user_id.is_none() && temp_group_id.is_none() {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Missing token" }))).into_response();
+    }
+
+    match search_messages(user_id, temp_group_id, &query, &state.db).await {
+        Ok(mut results) => {
+            // one extra row was fetched to know whether another page exists
+            let has_more = results.len() as i64 > query.limit;
+            results.truncate(query.limit as usize);
+            let next_cursor = if has_more { results.last().map(|r| r.id) } else { None };
+
+            (StatusCode::OK, Json(json!({
+                "results": results,
+                "next_cursor": next_cursor,
+            }))).into_response()
+        }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to search messages" })),
+            ).into_response()
+        }
+    }
+}
@@ -2,14 +2,15 @@ use std::{collections::HashMap, sync::Arc};
 use axum::{extract::{self, Query, State}, http::StatusCode, response::IntoResponse, routing::{get, post}, Json, Router};
 use gauth::validate_token;
 use serde_json::json;
-use sqlx::PgPool;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use bcrypt::verify;
 
 
+use crate::socket::broadcast_message;
+use crate::storage::attachment::delete_files;
 use crate::{state::ServerState, utils::queries::{delete_group, fetch_temp_chats_for_user}};
-use crate::utils::types::CreateTempGroupForm;
+use crate::utils::types::{CreateTempGroupForm, MessagePage, ServerEvent};
 use crate::utils::queries::{create_temp_chat, fetch_messages, fetch_temp_chat};
 
 pub fn router() -> Router<Arc<ServerState>> {
@@ -44,7 +45,7 @@ async fn get_temp_groups_for_user (
             
             let mut valid_temp_chats = Vec::new();
             for chat in temp_chats {
-                match check_end_date(chat.end_date, chat.group_id, &state.db).await {
+                match check_end_date(chat.end_date, chat.group_id, &state).await {
                     Ok(_) => {
                         valid_temp_chats.push(chat);
                     },
@@ -132,7 +133,7 @@ async fn get_group_info(
     match fetch_temp_chat(temp_chat_key, &state.db).await {
         Ok(temp_chat_info) => {
 
-            match check_end_date(temp_chat_info.end_date, temp_chat_info.group_id, &state.db).await {
+            match check_end_date(temp_chat_info.end_date, temp_chat_info.group_id, &state).await {
                 Ok(_) => {}
                 Err(_) => {
                     return (
@@ -204,7 +205,7 @@ async fn get_group_messages(
         }
     };
 
-    match check_end_date(temp_chat_info.end_date, temp_chat_info.group_id, &state.db).await {
+    match check_end_date(temp_chat_info.end_date, temp_chat_info.group_id, &state).await {
         Ok(_) => {}
         Err(_) => {
             return (
@@ -231,20 +232,19 @@ async fn get_group_messages(
     }
     
 
-    match fetch_messages(temp_chat_info.group_id, &state.db).await {
-        Ok(messages) => {
-            let message_data = messages.iter().map(|m| {
-                json!({
-                    "id": m.id,
-                    "content": m.content,
-                    "user_id": m.user_id,
-                    "username": m.username,
-                    "timestamp": m.timestamp.to_rfc3339(),
-                    "profile_picture": m.profile_picture
-                })
-            }).collect::<Vec<_>>();
-            
-            (StatusCode::OK, Json(message_data)).into_response()
+    let page = match MessagePage::from_params(&params) {
+        Ok(page) => page,
+        Err(error) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response();
+        }
+    };
+
+    match fetch_messages(temp_chat_info.group_id, None, &page, &state.db).await {
+        Ok(history) => {
+            (StatusCode::OK, Json(json!({
+                "messages": history.messages,
+                "next_cursor": history.next_cursor,
+            }))).into_response()
         },
         Err(err) => {
             eprintln!("Database error: {}", err);
@@ -256,13 +256,16 @@ async fn get_group_messages(
     }
 }
 
-pub async fn check_end_date(end_date: DateTime<Utc>, group_id: i32, db: &PgPool) -> Result<(), ()> {
+pub async fn check_end_date(end_date: DateTime<Utc>, group_id: i32, state: &Arc<ServerState>) -> Result<(), ()> {
     let now = Utc::now();
     if end_date > now {
         return Ok(());
     }
-    match delete_group(group_id, db).await {
-        Ok(_) => {
+    match delete_group(group_id, &state.db).await {
+        Ok(attachment_keys) => {
+            // closes the sockets still open on the expired chat
+            broadcast_message(state.clone(), group_id, &ServerEvent::GroupDeleted { group_id }).await;
+            delete_files(state.storage.as_ref(), &attachment_keys).await;
             return Err(());
         }
         Err(_) => {
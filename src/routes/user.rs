@@ -1,12 +1,17 @@
 use std::{collections::HashMap, sync::Arc};
-use axum::{extract::{Query, State}, http::StatusCode, response::IntoResponse, routing::{get, post}, Extension, Form, Json, Router};
+use axum::{body::Body, extract::{Query, State}, http::StatusCode, response::IntoResponse, routing::{get, post, put}, Extension, Form, Json, Router};
 use gauth::validate_token;
 use serde_json::json;
 use gauth::models::{Auth, Claims, User};
 use std::time::SystemTime;
 
-use crate::utils::{queries::fetch_stats, types::{LoginForm, RegisterForm}};
+use crate::utils::{queries::{change_user_picture, fetch_stats}, types::{LoginForm, RegisterForm}};
+use crate::routes::picture::{remove_replaced_picture, upload_picture};
+use crate::storage::picture::{delete_picture, picture_url};
 use crate::state::ServerState;
+use crate::socket::presence::fetch_presence;
+
+const MAX_PRESENCE_IDS: usize = 100;
 
 
 
@@ -18,6 +23,8 @@ pub fn router() -> Router<Arc<ServerState>> {
     .route("/check-token", get(check_token))
     .route("/get-user-info", get(get_user_info))
     .route("/get-user-stats", get(get_user_stats))
+    .route("/presence", get(get_presence))
+    .route("/edit-picture", put(edit_user_picture))
 }
 
 async fn check_token(
@@ -56,7 +63,8 @@ async fn handle_registration(
         id: None,
         username: form.username,
         email: Some(form.email),
-        profile_picture: form.profile_picture,
+        // set afterwards with /user/edit-picture
+        profile_picture: None,
         password: form.password, // Note: You should hash this password
         created_at: None,
     };
@@ -218,4 +226,87 @@ async fn get_user_stats(
         }
     }
 
-}
\ No newline at end of file
+}
+
+async fn get_presence(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let token = match params.get("token") {
+        None => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Missing token" }))).into_response()
+        }
+        Some(token) => token,
+    };
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    // comma separated, e.g. user_ids=1,2,3
+    let user_ids = match params.get("user_ids") {
+        None => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Missing user_ids" }))).into_response()
+        }
+        Some(user_ids) => user_ids.split(',').map(|id| id.trim().parse::<i32>()).collect::<Result<Vec<_>, _>>(),
+    };
+    let user_ids = match user_ids {
+        Ok(user_ids) if user_ids.len() <= MAX_PRESENCE_IDS => user_ids,
+        Ok(_) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Too many user_ids" }))).into_response()
+        }
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Invalid user_ids" }))).into_response()
+        }
+    };
+
+    match fetch_presence(&state, user_id, &user_ids).await {
+        Ok(presence) => (StatusCode::OK, Json(presence)).into_response(),
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Failed to fetch presence" }))).into_response()
+        }
+    }
+}
+
+// The image is the raw request body, see routes::picture
+async fn edit_user_picture(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+    body: Body,
+) -> impl IntoResponse {
+    let token = match params.get("token") {
+        None => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Missing token" }))).into_response()
+        }
+        Some(token) => token,
+    };
+    let jwt_key = std::env::var("JWT_KEY").expect("JWT_KEY must be set");
+    let user_id = match validate_token(token, jwt_key).await {
+        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Invalid token" }))).into_response()
+        }
+    };
+
+    let picture_id = match upload_picture(&state, body).await {
+        Ok(picture_id) => picture_id,
+        Err(response) => return response,
+    };
+
+    let url = picture_url(picture_id);
+    match change_user_picture(user_id, url.clone(), &state.db).await {
+        Ok(old_url) => {
+            remove_replaced_picture(&state, old_url).await;
+            (StatusCode::OK, Json(json!({ "message": "Picture Updated", "picture_url": url }))).into_response()
+        }
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            delete_picture(&state.storage, picture_id).await;
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Failed to update picture" }))).into_response()
+        }
+    }
+}
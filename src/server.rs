@@ -1,47 +1,45 @@
 mod state;
 mod routes;
+mod socket;
+mod storage;
 mod utils;
 
-use axum::extract::ws::WebSocket;
 use axum::http::header;
 use axum::http::{HeaderValue, Method};
-use axum::extract::Path;
-use axum::http::StatusCode;
 use axum::routing::get;
 use axum::Extension;
-use axum::{
-    extract::{ws::Message, Query, State, WebSocketUpgrade},
-    response::IntoResponse,
-    routing::Router,
-    Json,
-};
+use axum::routing::Router;
 
-use bcrypt::verify;
 use dotenv::dotenv;
-use futures_util::{SinkExt, StreamExt};
 use gauth::models::Auth;
-use gauth::validate_token;
-use routes::temp_group::check_end_date;
-use serde_json::json;
+use socket::fanout::Fanout;
+use socket::heartbeat::HeartbeatConfig;
+use socket::limits::{MessageLimits, RateLimiter};
+use socket::queue::{QueueConfig, QueueStats};
+use socket::registry::ChannelRegistry;
+use socket::shutdown::Shutdown;
 use sqlx::postgres::PgPoolOptions;
 use state::ServerState;
-use uuid::Uuid;
 
 use std::sync::Arc;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use tokio::net::TcpListener;
 use tokio::sync::{mpsc, Mutex};
 
 use tower_http::cors::CorsLayer;
-use utils::queries::{fetch_group_type, get_temp_info_with_group_id, insert_message_in_db, is_user_in_group};
+
+// how long open sockets get to finish what they're doing once a shutdown begins
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
     let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    storage::picture::check_public_url();
 
     let auth = Auth::new(db_url.clone())
         .await
@@ -55,14 +53,36 @@ async fn main() {
         .await
         .expect("Failed to create pool");
 
+    let message_limits = MessageLimits::from_env();
+    let (presence_updates, presence_rx) = mpsc::unbounded_channel();
+
     // Shared DB state
     let state = ServerState {
-        db: pool,
-        channels: Arc::new(Mutex::new(HashMap::new()))
+        db: pool.clone(),
+        channels: Arc::new(ChannelRegistry::default()),
+        users: Arc::new(Mutex::new(HashMap::new())),
+        presence_updates,
+        queue_config: QueueConfig::from_env(),
+        queue_stats: Arc::new(QueueStats::default()),
+        fanout: Arc::new(Fanout::start(pool.clone())),
+        heartbeat: HeartbeatConfig::from_env(),
+        shutdown: Arc::new(Shutdown::default()),
+        message_limits,
+        rate_limiter: Arc::new(RateLimiter::new(message_limits)),
+        storage: storage::from_env(),
     };
 
     let state = std::sync::Arc::new(state);
 
+    socket::fanout::register(&state)
+        .await
+        .expect("Failed to register the server instance");
+    // picks up events published by the other instances
+    tokio::spawn(socket::fanout::listen(state.clone()));
+    tokio::spawn(socket::fanout::heartbeat(state.clone()));
+    tokio::spawn(socket::presence::write(state.clone(), presence_rx));
+    tokio::spawn(storage::attachment::expire_unsent(state.clone()));
+
     // Configure CORS with proper origin matching
     let cors = CorsLayer::new()
         .allow_origin([
@@ -90,188 +110,67 @@ async fn main() {
 
     let app = Router::new()
         .route("/ping", get(|| async { "pong" }))
-        .route("/ws/group/:group_id", get(ws_handler))
+        .route("/ws", get(socket::ws_handler))
+        .route("/ws/group/:group_id", get(socket::group_ws_handler))
+        .route("/ws/stats", get(socket::queue_stats))
         .nest("/", routes::app_routes().with_state(state.clone()))
         .layer(cors)
         .layer(Extension(auth))
-        .with_state(state);
+        .with_state(state.clone());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
     println!("Server running on {}", addr);
     let listener = TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-}
-
-
-async fn ws_handler(
-    ws: WebSocketUpgrade,
-    State(state): State<Arc<ServerState>>,
-    Path(group_id): Path<i32>,
-    Query(params): Query<HashMap<String, String>>,
-) -> impl IntoResponse {
-
-    let token = params.get("token").cloned();
-    if token.is_none() {
-        return (StatusCode::UNAUTHORIZED, "Missing authentication token").into_response();
-    }
-    dotenv().ok();
-    let key = std::env::var("JWT_KEY").expect("Must set JWT_KEY environment variable");
-    let token = token.unwrap();
-
-    let user_id = match validate_token(&token, key).await {
-        Ok(claims) => claims.sub.parse::<i32>().unwrap(),
-        Err(_) => return (StatusCode::UNAUTHORIZED, "Invalid token").into_response(),
-    };
 
-    let group_type = match fetch_group_type(group_id, &state.db).await {
-        Ok(group_type) => group_type,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch group type").into_response(),
+    let shutdown_timeout = match std::env::var("SHUTDOWN_TIMEOUT_SECS") {
+        Ok(secs) => Duration::from_secs(secs.parse::<u64>().expect("SHUTDOWN_TIMEOUT_SECS must be a number")),
+        Err(_) => DEFAULT_SHUTDOWN_TIMEOUT,
     };
 
-    if group_type == 3 {
-        let temp_chat_info = match get_temp_info_with_group_id(group_id, &state.db).await {
-            Ok(temp_chat_info) => temp_chat_info,
-            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response(),
-        };
-
-        match check_end_date(temp_chat_info.end_date, group_id, &state.db).await {
-            Ok(_) => {}
-            Err(_) => {
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
-            }
-        }
+    // stops accepting connections once shutdown begins and finishes the requests already in flight
+    let shutdown = state.shutdown.clone();
+    let server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { shutdown.closing().await })
+            .await
+            .unwrap();
+    });
 
-        let password = params.get("password");
-        let password = match password {
-            Some(password) => password.parse::<String>().unwrap(),
-            None => {
-                return (StatusCode::UNAUTHORIZED, "Missing password").into_response();
-            }
-        };
-        if !(verify(password, &temp_chat_info.password).unwrap_or(false)) {
-            return (StatusCode::UNAUTHORIZED, "Invalid password").into_response();
-        }
-        
-        return ws.on_upgrade(move |socket: WebSocket| handle_socket(socket, user_id, state, group_id));
-    }
+    shutdown_signal().await;
+    println!("Shutting down, closing {} sockets", state.shutdown.open_connections());
+    state.shutdown.begin();
 
-    match is_user_in_group(user_id, group_id, &state.db).await {
-        Ok(_) => {},
-        Err(_) => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({ "error": "Unauthorized" })),
-            )
-            .into_response()
-        }
+    let drained = async {
+        let _ = server.await;
+        state.shutdown.drained().await;
+        // closing sockets publishes presence changes, make sure they go out before the pool closes
+        socket::presence::stop(&state).await;
+        state.fanout.flush().await;
+    };
+    if tokio::time::timeout(shutdown_timeout, drained).await.is_err() {
+        eprintln!("Shutdown timed out with {} sockets still open", state.shutdown.open_connections());
     }
-
-    ws.on_upgrade(move |socket: WebSocket| handle_socket(socket, user_id, state, group_id))
-
+    pool.close().await;
 }
 
-async fn handle_socket(socket: WebSocket, user_id: i32, state: std::sync::Arc<ServerState>, group_id: i32) {
-    let connection_id = Uuid::new_v4(); 
-
-    let (mut sender, mut receiver) = socket.split();
-    let (mpsc_tx, mut mpsc_rx) = mpsc::unbounded_channel::<Message>();
-
-    {        
-        let mut channels = state.channels.lock().await;
-        let channel = channels.entry(group_id).or_default();
-        channel.insert(connection_id, mpsc_tx.clone());
-    }
-
-    // Task to broadcast messages to this client
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = mpsc_rx.recv().await {
-            if sender.send(msg).await.is_err() {
-                eprintln!("error trying to send message, connection_id: {}", connection_id);
-                break;
-            }
-        }
-    });
-
-    let state_clone = state.clone();
-    // Task to handle messages from this client
-    let recv_task = tokio::spawn(async move {
-        while let Some(result) = receiver.next().await {
-            match result {
-                Ok(msg) => {
-                    match msg {
-                        Message::Text(text_content) => {    
-                            // convert result to json and send to all clients
-                            match insert_message_in_db(user_id, group_id, text_content, &state_clone.db).await {
-                                Ok(record) => {
-                                    let message_json = json!({
-                                        "id": record.id,
-                                        "user_id": record.user_id,
-                                        "content": record.content,
-                                        "timestamp": record.timestamp.to_rfc3339(),
-                                        "username": record.username,
-                                        "profile_picture": record.profile_picture,
-                                        "group_id": record.group_id
-                                    });
-                                    
-                                    let broadcast_msg = Message::Text(message_json.to_string());
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c().await.expect("Failed to listen for SIGINT");
+    };
 
-                                    broadcast_message(
-                                        state_clone.clone(),
-                                        group_id,
-                                        broadcast_msg
-                                    ).await;
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to store message: {}", e);
-                                }
-                            }
-                        }
-                        Message::Close(_) => {}
-                        _ =>{}
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error receiving message, connection_id: {}, {:?}", connection_id, e)
-                }
-            }
-        }
-    });
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    // Wait for either task to complete
     tokio::select! {
-        _ = send_task => {},
-        _ = recv_task => {},
-    }
-
-    //clean up channles
-    {
-        let mut channels = state.channels.lock().await;
-        if let Some(channel) = channels.get_mut(&group_id) {
-            channel.remove(&connection_id);
-
-            if channel.is_empty() {
-                channels.remove(&group_id);
-            }
-        }
+        _ = interrupt => {},
+        _ = terminate => {},
     }
 }
 
-//helper function to broadcast messages
-async fn broadcast_message(
-    state: Arc<ServerState>,
-    group_id: i32,
-    msg: Message,
-) {
-    let channels = state.channels.lock().await;
-
-    if let Some(channel) = channels.get(&group_id) {
-        for (peer_connection_id, peer_tx) in channel.iter() {
-            if peer_tx.send(msg.clone()).is_err() {
-                eprintln!(
-                    "Failed to send message to {}",
-                    peer_connection_id
-                );
-            }
-        }
-    }
-}
\ No newline at end of file
@@ -0,0 +1,415 @@
+use axum::extract::ws::Message;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::socket::presence::{self, PresenceUpdate};
+use crate::state::ServerState;
+use crate::utils::queries::{
+    count_live_peers, fetch_lost_group_ids, fetch_relayed_event, notify, notify_all, prune_relayed_events, remove_instances, store_relayed_event,
+    touch_instance,
+};
+use crate::utils::types::{Outbound, RemovedMember, ServerEvent};
+
+const EVENTS_CHANNEL: &str = "gchat_events";
+// the payload is a relayed_events id, for events too large to fit in a notification
+const STORED_EVENTS_CHANNEL: &str = "gchat_stored_events";
+// postgres rejects NOTIFY payloads of 8000 bytes or more
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+const LISTEN_RETRY: Duration = Duration::from_secs(1);
+// events waiting to be published, once it's full new ones are dropped so senders never wait on the database,
+// except control events which wait for room
+const PUBLISH_QUEUE_SIZE: usize = 4096;
+// events published together in one round-trip
+const PUBLISH_BATCH_SIZE: usize = 256;
+// instances refresh their server_instances row this often
+const INSTANCE_HEARTBEAT: Duration = Duration::from_secs(10);
+// and are taken for dead, along with their users' presence, after missing a few
+pub const INSTANCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Who an event is for, resolved against the connections of whichever instance delivers it
+#[derive(Serialize, Deserialize, Default)]
+pub struct Audience {
+    pub groups: Vec<i32>,
+    pub users: Vec<i32>,
+    // set when the connection or user the event came from shouldn't get it back
+    pub skip_connection: Option<Uuid>,
+    pub skip_user: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    origin: Uuid,
+    audience: Audience,
+    message_id: Option<i32>,
+    deleted_group: Option<i32>,
+    removed_member: Option<RemovedMember>,
+    #[serde(default)]
+    control: bool,
+    frame: String,
+}
+
+enum Job {
+    Publish(Envelope),
+    // answered once everything queued before it is published
+    Flush(oneshot::Sender<()>),
+}
+
+// Relays events between server instances through Postgres LISTEN/NOTIFY so they can run behind a load balancer
+pub struct Fanout {
+    instance_id: Uuid,
+    tx: mpsc::Sender<Job>,
+    // assumed until the first heartbeat has counted them
+    has_peers: AtomicBool,
+    dropped_events: AtomicU64,
+}
+
+impl Fanout {
+    // spawns the task publishing this instance's events in batches, in order, so other instances get them in order
+    pub fn start(db: PgPool) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Job>(PUBLISH_QUEUE_SIZE);
+
+        tokio::spawn(async move {
+            let mut jobs = Vec::with_capacity(PUBLISH_BATCH_SIZE);
+            while rx.recv_many(&mut jobs, PUBLISH_BATCH_SIZE).await > 0 {
+                publish_batch(jobs.drain(..), &db).await;
+            }
+        });
+
+        Fanout {
+            instance_id: Uuid::new_v4(),
+            tx,
+            has_peers: AtomicBool::new(true),
+            dropped_events: AtomicU64::new(0),
+        }
+    }
+
+    pub fn instance_id(&self) -> Uuid {
+        self.instance_id
+    }
+
+    // ephemeral and chat events that never reached the other instances because the publish queue was full
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    // waits for the events sent so far to reach the other instances, used on shutdown
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.tx.send(Job::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+// notifications that fit are sent together, an oversized one first sends the ones queued before it
+async fn publish_batch(jobs: impl Iterator<Item = Job>, db: &PgPool) {
+    let mut payloads = Vec::new();
+    for job in jobs {
+        match job {
+            Job::Publish(envelope) => {
+                let payload = serde_json::to_string(&envelope).expect("envelopes always serialize");
+                if payload.len() <= MAX_NOTIFY_PAYLOAD {
+                    payloads.push(payload);
+                    continue;
+                }
+                publish_notifications(&mut payloads, db).await;
+                if let Err(e) = publish_stored(&payload, db).await {
+                    eprintln!("Failed to relay event: {}", e);
+                }
+            }
+            Job::Flush(done) => {
+                publish_notifications(&mut payloads, db).await;
+                let _ = done.send(());
+            }
+        }
+    }
+    publish_notifications(&mut payloads, db).await;
+}
+
+async fn publish_notifications(payloads: &mut Vec<String>, db: &PgPool) {
+    if payloads.is_empty() {
+        return;
+    }
+    if let Err(e) = notify_all(EVENTS_CHANNEL, payloads, db).await {
+        eprintln!("Failed to relay {} events: {}", payloads.len(), e);
+    }
+    payloads.clear();
+}
+
+async fn publish_stored(payload: &str, db: &PgPool) -> Result<(), sqlx::Error> {
+    let id = store_relayed_event(payload, db).await?;
+    notify(STORED_EVENTS_CHANNEL, &id.to_string(), db).await?;
+    prune_relayed_events(db).await
+}
+
+// Delivers the event to the matching connections on this instance and relays it to the others
+pub async fn send(state: &Arc<ServerState>, audience: Audience, event: &ServerEvent) {
+    let frame = event.to_text();
+    let outbound = Outbound {
+        message_id: event.message_id(),
+        deleted_group: event.deleted_group(),
+        removed_member: event.removed_member(),
+        control: event.is_control(),
+        frame: Message::Text(frame.clone()),
+    };
+    deliver(state, &audience, &outbound).await;
+
+    // typing is only worth relaying while someone else could be listening
+    if event.is_ephemeral() && !state.fanout.has_peers.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let envelope = Envelope {
+        origin: state.fanout.instance_id,
+        audience,
+        message_id: outbound.message_id,
+        deleted_group: outbound.deleted_group,
+        removed_member: outbound.removed_member,
+        control: outbound.control,
+        frame,
+    };
+    // a lost removal or deletion would leave access open on the other instances, so those are never dropped
+    if outbound.control {
+        if state.fanout.tx.send(Job::Publish(envelope)).await.is_err() {
+            eprintln!("Event relay is not running");
+        }
+        return;
+    }
+    match state.fanout.tx.try_send(Job::Publish(envelope)) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            state.fanout.dropped_events.fetch_add(1, Ordering::Relaxed);
+            // a lost typing event is refreshed by the next one, anything else is worth knowing about
+            if !event.is_ephemeral() {
+                eprintln!("Event relay is falling behind, dropped an event");
+            }
+        }
+        Err(TrySendError::Closed(_)) => eprintln!("Event relay is not running"),
+    }
+}
+
+async fn deliver(state: &Arc<ServerState>, audience: &Audience, outbound: &Outbound) {
+    // a single group can't reach the same connection twice
+    if audience.groups.len() <= 1 && audience.users.is_empty() && audience.skip_user.is_none() {
+        if let Some(group_id) = audience.groups.first() {
+            state.channels.broadcast(*group_id, audience.skip_connection, outbound);
+        }
+    } else {
+        deliver_once(state, audience, outbound).await;
+    }
+
+    if let Some(group_id) = outbound.deleted_group {
+        state.channels.remove_group(group_id);
+    }
+    // after the event went out, so the removed member's connections get it too
+    if let Some(removed) = outbound.removed_member {
+        let connections = state
+            .users
+            .lock()
+            .await
+            .get(&removed.user_id)
+            .map(|connections| connections.iter().map(|(id, c)| (*id, c.joined.clone())).collect::<Vec<_>>())
+            .unwrap_or_default();
+        for (connection_id, joined) in connections {
+            state.channels.unsubscribe(removed.group_id, connection_id);
+            joined.lock().unwrap().remove(&removed.group_id);
+        }
+    }
+}
+
+async fn deliver_once(state: &Arc<ServerState>, audience: &Audience, outbound: &Outbound) {
+
+    let mut recipients = HashMap::new();
+    let skipped = {
+        let users = state.users.lock().await;
+        for user_id in audience.users.iter() {
+            if let Some(connections) = users.get(user_id) {
+                for (connection_id, connection) in connections {
+                    recipients.insert(*connection_id, connection.tx.clone());
+                }
+            }
+        }
+        audience
+            .skip_user
+            .and_then(|user_id| users.get(&user_id))
+            .map(|connections| connections.keys().copied().collect::<Vec<_>>())
+            .unwrap_or_default()
+    };
+    for group_id in audience.groups.iter() {
+        state.channels.collect_connections(*group_id, &mut recipients);
+    }
+    for connection_id in skipped.into_iter().chain(audience.skip_connection) {
+        recipients.remove(&connection_id);
+    }
+
+    for (connection_id, tx) in recipients {
+        if tx.send(outbound.clone()).is_err() {
+            eprintln!("Failed to send message to {}", connection_id);
+        }
+    }
+}
+
+async fn connect_listener(state: &Arc<ServerState>) -> Option<PgListener> {
+    loop {
+        match PgListener::connect_with(&state.db).await {
+            Ok(mut listener) => match listener.listen_all([EVENTS_CHANNEL, STORED_EVENTS_CHANNEL]).await {
+                Ok(_) => return Some(listener),
+                Err(e) => eprintln!("Failed to listen for relayed events: {}", e),
+            },
+            Err(e) => eprintln!("Failed to connect the event listener: {}", e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(LISTEN_RETRY) => {}
+            _ = state.shutdown.closing() => return None,
+        }
+    }
+}
+
+// Forwards events published by the other instances to the connections on this one
+pub async fn listen(state: Arc<ServerState>) {
+    let Some(mut listener) = connect_listener(&state).await else {
+        return;
+    };
+
+    loop {
+        // unlike recv, try_recv says when the connection was lost instead of quietly reconnecting
+        let notification = tokio::select! {
+            notification = listener.try_recv() => notification,
+            _ = state.shutdown.closing() => return,
+        };
+        let notification = match notification {
+            Ok(Some(notification)) => notification,
+            lost => {
+                match lost {
+                    Err(e) => eprintln!("Lost connection to the event listener: {}", e),
+                    _ => eprintln!("Lost connection to the event listener"),
+                }
+                drop(listener);
+                listener = match connect_listener(&state).await {
+                    Some(listener) => listener,
+                    None => return,
+                };
+                // removals relayed while it was gone never arrived, so access is checked against the database instead
+                recheck_access(&state).await;
+                continue;
+            }
+        };
+
+        let payload = if notification.channel() == STORED_EVENTS_CHANNEL {
+            let stored = match notification.payload().parse::<i64>() {
+                Ok(id) => fetch_relayed_event(id, &state.db).await,
+                Err(_) => Err(sqlx::Error::RowNotFound),
+            };
+            match stored {
+                Ok(payload) => payload,
+                Err(e) => {
+                    eprintln!("Failed to fetch relayed event {}: {}", notification.payload(), e);
+                    continue;
+                }
+            }
+        } else {
+            notification.payload().to_string()
+        };
+
+        let envelope = match serde_json::from_str::<Envelope>(&payload) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                eprintln!("Malformed relayed event: {}", e);
+                continue;
+            }
+        };
+        // already delivered locally when it was sent
+        if envelope.origin == state.fanout.instance_id {
+            continue;
+        }
+
+        let outbound = Outbound {
+            message_id: envelope.message_id,
+            deleted_group: envelope.deleted_group,
+            removed_member: envelope.removed_member,
+            control: envelope.control,
+            frame: Message::Text(envelope.frame),
+        };
+        deliver(&state, &envelope.audience, &outbound).await;
+    }
+}
+
+// unsubscribes local connections from the groups their users are no longer in, the same way a relayed removal would
+async fn recheck_access(state: &Arc<ServerState>) {
+    let joined_by_user = state
+        .users
+        .lock()
+        .await
+        .iter()
+        .map(|(user_id, connections)| {
+            let joined = connections.values().map(|c| c.joined.clone()).collect::<Vec<_>>();
+            (*user_id, joined)
+        })
+        .collect::<Vec<_>>();
+
+    for (user_id, connections) in joined_by_user {
+        let group_ids = connections
+            .iter()
+            .flat_map(|joined| joined.lock().unwrap().iter().copied().collect::<Vec<_>>())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let lost = match fetch_lost_group_ids(user_id, &group_ids, &state.db).await {
+            Ok(lost) => lost,
+            Err(e) => {
+                eprintln!("Failed to recheck groups for user {}: {}", user_id, e);
+                continue;
+            }
+        };
+        for group_id in lost {
+            let event = ServerEvent::MemberRemoved(RemovedMember { group_id, user_id });
+            let audience = Audience { users: vec![user_id], ..Default::default() };
+            deliver(state, &audience, &event.to_outbound()).await;
+        }
+    }
+}
+
+// registers this instance before it takes connections, so its presence rows have somewhere to point
+pub async fn register(state: &Arc<ServerState>) -> Result<(), sqlx::Error> {
+    touch_instance(state.fanout.instance_id, &state.db).await?;
+    Ok(())
+}
+
+// keeps this instance marked alive, counts the others and clears out the ones that died without saying so
+pub async fn heartbeat(state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(INSTANCE_HEARTBEAT);
+    let timeout = INSTANCE_TIMEOUT.as_secs_f64();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.closing() => return,
+        }
+
+        match touch_instance(state.fanout.instance_id, &state.db).await {
+            Ok(true) => {
+                eprintln!("Server instance was taken for dead, recording its presence again");
+                let _ = state.presence_updates.send(PresenceUpdate::Resync);
+            }
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to refresh the server instance: {}", e),
+        }
+        match count_live_peers(state.fanout.instance_id, timeout, &state.db).await {
+            Ok(peers) => state.fanout.has_peers.store(peers > 0, Ordering::Relaxed),
+            Err(e) => eprintln!("Failed to count server instances: {}", e),
+        }
+        match remove_instances(None, timeout, &state.db).await {
+            Ok(lost) => presence::instances_lost(&state, lost).await,
+            Err(e) => eprintln!("Failed to remove dead server instances: {}", e),
+        }
+    }
+}
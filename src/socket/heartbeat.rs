@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub ping_interval: Duration,
+    // how long past a missed ping the peer gets before the connection is dropped
+    pub pong_timeout: Duration,
+}
+
+impl HeartbeatConfig {
+    // WS_PING_INTERVAL_SECS and WS_PONG_TIMEOUT_SECS
+    pub fn from_env() -> Self {
+        HeartbeatConfig {
+            ping_interval: seconds_from_env("WS_PING_INTERVAL_SECS").unwrap_or(DEFAULT_PING_INTERVAL),
+            pong_timeout: seconds_from_env("WS_PONG_TIMEOUT_SECS").unwrap_or(DEFAULT_PONG_TIMEOUT),
+        }
+    }
+}
+
+fn seconds_from_env(name: &str) -> Option<Duration> {
+    let value = std::env::var(name).ok()?;
+    let seconds = value.parse::<u64>().ok().filter(|s| *s > 0);
+    Some(Duration::from_secs(seconds.unwrap_or_else(|| panic!("{} must be a positive number of seconds", name))))
+}
+
+// When the peer was last heard from, any frame counts, not just pongs
+pub struct Liveness {
+    started: Instant,
+    last_heard_us: AtomicU64,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Liveness { started: Instant::now(), last_heard_us: AtomicU64::new(0) }
+    }
+}
+
+impl Liveness {
+    pub fn touch(&self) {
+        let elapsed = self.started.elapsed().as_micros() as u64;
+        self.last_heard_us.store(elapsed, Ordering::Relaxed);
+    }
+
+    pub fn heard_since(&self, since: Instant) -> bool {
+        let last_heard = self.started + Duration::from_micros(self.last_heard_us.load(Ordering::Relaxed));
+        last_heard >= since
+    }
+}
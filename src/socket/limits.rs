@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_MESSAGE_LENGTH: usize = 4000;
+const DEFAULT_MAX_ATTACHMENT_SIZE: u32 = 25 * 1024 * 1024;
+const DEFAULT_USER_RATE: Rate = Rate { burst: 10, per_minute: 60 };
+const DEFAULT_GROUP_RATE: Rate = Rate { burst: 50, per_minute: 600 };
+// frames this many times larger than the longest valid message close the connection
+const FRAME_SIZE_HEADROOM: usize = 16;
+// buckets that filled back up are forgotten once there are this many, a full bucket is the same as none
+const PRUNE_THRESHOLD: usize = 10_000;
+
+// How many messages can be sent at once and how fast they're allowed after that
+#[derive(Clone, Copy)]
+pub struct Rate {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct MessageLimits {
+    // in characters, after trimming
+    pub max_length: usize,
+    // in bytes
+    pub max_attachment_size: usize,
+    pub user_rate: Rate,
+    pub group_rate: Rate,
+}
+
+impl MessageLimits {
+    // MAX_MESSAGE_LENGTH, MAX_ATTACHMENT_SIZE, USER_MESSAGE_BURST, USER_MESSAGES_PER_MINUTE, GROUP_MESSAGE_BURST and GROUP_MESSAGES_PER_MINUTE
+    pub fn from_env() -> Self {
+        MessageLimits {
+            max_length: number_from_env("MAX_MESSAGE_LENGTH").unwrap_or(DEFAULT_MAX_MESSAGE_LENGTH as u32) as usize,
+            max_attachment_size: number_from_env("MAX_ATTACHMENT_SIZE").unwrap_or(DEFAULT_MAX_ATTACHMENT_SIZE) as usize,
+            user_rate: Rate {
+                burst: number_from_env("USER_MESSAGE_BURST").unwrap_or(DEFAULT_USER_RATE.burst),
+                per_minute: number_from_env("USER_MESSAGES_PER_MINUTE").unwrap_or(DEFAULT_USER_RATE.per_minute),
+            },
+            group_rate: Rate {
+                burst: number_from_env("GROUP_MESSAGE_BURST").unwrap_or(DEFAULT_GROUP_RATE.burst),
+                per_minute: number_from_env("GROUP_MESSAGES_PER_MINUTE").unwrap_or(DEFAULT_GROUP_RATE.per_minute),
+            },
+        }
+    }
+
+    // trims the content and rejects it if it's too long, or empty unless the message has attachments
+    pub fn check_content(&self, content: &str, allow_empty: bool) -> Result<String, String> {
+        let content = content.trim();
+        if content.is_empty() && !allow_empty {
+            return Err("Message cannot be empty".to_string());
+        }
+        if content.chars().count() > self.max_length {
+            return Err(format!("Message cannot be longer than {} characters", self.max_length));
+        }
+        Ok(content.to_string())
+    }
+
+    // in bytes, a json escaped character takes up to 6 and the rest of the frame is small. Kept well past
+    // what a valid message needs, so one that's too long still reaches check_content and gets invalid_content
+    pub fn max_frame_size(&self) -> usize {
+        (self.max_length * 6 + 4096) * FRAME_SIZE_HEADROOM
+    }
+}
+
+fn number_from_env(name: &str) -> Option<u32> {
+    let value = std::env::var(name).ok()?;
+    let number = value.parse::<u32>().ok().filter(|n| *n > 0);
+    Some(number.unwrap_or_else(|| panic!("{} must be a positive number", name)))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LimitScope {
+    User,
+    Group,
+}
+
+pub struct Limited {
+    pub scope: LimitScope,
+    pub retry_after: Duration,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let refilled = now.duration_since(self.updated).as_secs_f64() * rate.per_minute as f64 / 60.0;
+        self.tokens = (self.tokens + refilled).min(rate.burst as f64);
+        self.updated = now;
+    }
+
+    fn retry_after(&self, rate: Rate) -> Duration {
+        Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / rate.per_minute as f64)
+    }
+}
+
+// Token buckets per user and per group, a message takes a token from both
+pub struct RateLimiter {
+    limits: MessageLimits,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    users: HashMap<i32, Bucket>,
+    groups: HashMap<i32, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: MessageLimits) -> Self {
+        RateLimiter { limits, buckets: Mutex::default() }
+    }
+
+    pub fn check(&self, user_id: i32, group_id: i32) -> Result<(), Limited> {
+        self.check_at(user_id, group_id, Instant::now())
+    }
+
+    fn check_at(&self, user_id: i32, group_id: i32, now: Instant) -> Result<(), Limited> {
+        let limits = self.limits;
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { users, groups } = &mut *buckets;
+
+        if users.len() > PRUNE_THRESHOLD {
+            prune(users, limits.user_rate, now);
+        }
+        if groups.len() > PRUNE_THRESHOLD {
+            prune(groups, limits.group_rate, now);
+        }
+
+        let user = bucket(users, user_id, limits.user_rate, now);
+        if user.tokens < 1.0 {
+            return Err(Limited { scope: LimitScope::User, retry_after: user.retry_after(limits.user_rate) });
+        }
+        let group = bucket(groups, group_id, limits.group_rate, now);
+        if group.tokens < 1.0 {
+            return Err(Limited { scope: LimitScope::Group, retry_after: group.retry_after(limits.group_rate) });
+        }
+
+        // only taken once both allow it, so a rejected message doesn't use up the other bucket
+        group.tokens -= 1.0;
+        user.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+fn bucket(buckets: &mut HashMap<i32, Bucket>, id: i32, rate: Rate, now: Instant) -> &mut Bucket {
+    let bucket = buckets.entry(id).or_insert(Bucket { tokens: rate.burst as f64, updated: now });
+    bucket.refill(rate, now);
+    bucket
+}
+
+fn prune(buckets: &mut HashMap<i32, Bucket>, rate: Rate, now: Instant) {
+    buckets.retain(|_, bucket| {
+        bucket.refill(rate, now);
+        bucket.tokens < rate.burst as f64
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(user_rate: Rate, group_rate: Rate) -> MessageLimits {
+        MessageLimits { max_length: 5, max_attachment_size: 1024, user_rate, group_rate }
+    }
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn allows_a_burst_then_limits_the_user() {
+        let limiter = RateLimiter::new(limits(Rate { burst: 3, per_minute: 60 }, Rate { burst: 100, per_minute: 600 }));
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(1, 1, now).is_ok());
+        }
+
+        let limited = limiter.check_at(1, 1, now).unwrap_err();
+        assert!(limited.scope == LimitScope::User);
+        assert_eq!(limited.retry_after, SECOND);
+        // the user is limited in every group
+        assert!(limiter.check_at(1, 2, now).is_err());
+        assert!(limiter.check_at(2, 1, now).is_ok());
+    }
+
+    #[test]
+    fn refills_at_the_steady_rate() {
+        let limiter = RateLimiter::new(limits(Rate { burst: 2, per_minute: 60 }, Rate { burst: 100, per_minute: 600 }));
+        let now = Instant::now();
+        assert!(limiter.check_at(1, 1, now).is_ok());
+        assert!(limiter.check_at(1, 1, now).is_ok());
+
+        let half_refilled = limiter.check_at(1, 1, now + SECOND / 2).unwrap_err();
+        assert_eq!(half_refilled.retry_after, SECOND / 2);
+
+        assert!(limiter.check_at(1, 1, now + SECOND).is_ok());
+        assert!(limiter.check_at(1, 1, now + SECOND).is_err());
+        // never more than the burst, however long it waited
+        let later = now + SECOND * 60;
+        assert!(limiter.check_at(1, 1, later).is_ok());
+        assert!(limiter.check_at(1, 1, later).is_ok());
+        assert!(limiter.check_at(1, 1, later).is_err());
+    }
+
+    #[test]
+    fn limits_a_group_across_users() {
+        let limiter = RateLimiter::new(limits(Rate { burst: 10, per_minute: 60 }, Rate { burst: 2, per_minute: 30 }));
+        let now = Instant::now();
+        assert!(limiter.check_at(1, 1, now).is_ok());
+        assert!(limiter.check_at(2, 1, now).is_ok());
+
+        let limited = limiter.check_at(3, 1, now).unwrap_err();
+        assert!(limited.scope == LimitScope::Group);
+        assert_eq!(limited.retry_after, SECOND * 2);
+        assert!(limiter.check_at(3, 2, now).is_ok());
+    }
+
+    #[test]
+    fn rejected_messages_keep_the_user_tokens() {
+        let limiter = RateLimiter::new(limits(Rate { burst: 2, per_minute: 60 }, Rate { burst: 1, per_minute: 60 }));
+        let now = Instant::now();
+        assert!(limiter.check_at(1, 1, now).is_ok());
+        for _ in 0..5 {
+            assert!(limiter.check_at(1, 1, now).is_err());
+        }
+        assert!(limiter.check_at(1, 2, now).is_ok());
+    }
+
+    #[test]
+    fn trims_content() {
+        let limits = limits(DEFAULT_USER_RATE, DEFAULT_GROUP_RATE);
+        assert_eq!(limits.check_content("hi\n", false).unwrap(), "hi");
+        assert_eq!(limits.check_content("  hi \r\n\n", false).unwrap(), "hi");
+    }
+
+    #[test]
+    fn rejects_empty_content_without_attachments() {
+        let limits = limits(DEFAULT_USER_RATE, DEFAULT_GROUP_RATE);
+        assert!(limits.check_content("", false).is_err());
+        assert!(limits.check_content(" \n", false).is_err());
+        assert_eq!(limits.check_content(" \n", true).unwrap(), "");
+    }
+
+    #[test]
+    fn rejects_content_over_the_length_in_characters() {
+        let limits = limits(DEFAULT_USER_RATE, DEFAULT_GROUP_RATE);
+        assert_eq!(limits.check_content("héllo", false).unwrap(), "héllo");
+        assert_eq!(limits.check_content("hello\n", false).unwrap(), "hello");
+        assert!(limits.check_content("hello!", false).is_err());
+    }
+}
@@ -0,0 +1,942 @@
+pub mod fanout;
+pub mod heartbeat;
+pub mod limits;
+pub mod presence;
+pub mod queue;
+pub mod registry;
+pub mod shutdown;
+
+use axum::extract::ws::{CloseFrame, WebSocket};
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{
+    extract::{ws::Message, Query, State, WebSocketUpgrade},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use bcrypt::verify;
+use dotenv::dotenv;
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use gauth::validate_token;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::routes::temp_group::check_end_date;
+use crate::socket::fanout::Audience;
+use crate::socket::heartbeat::Liveness;
+use crate::socket::limits::{LimitScope, Limited};
+use crate::socket::queue::QueueSender;
+use crate::socket::registry::{ChannelRegistry, JoinedGroups};
+use crate::state::ServerState;
+use crate::storage::attachment::delete_files;
+use crate::utils::queries::{add_reaction, can_delete_message, can_post_in_group, delete_message, edit_message, fetch_group_type, fetch_group_ids_for_user, fetch_lost_group_ids, fetch_message_ref, fetch_messages_since, fetch_replay_start, fetch_reaction_count, fetch_username, get_temp_info_with_group_id, insert_message_in_db, is_user_in_group, mark_read, remove_reaction};
+use crate::utils::types::{check_emoji, ClientEvent, ClientFrame, ErrorCode, MessageRef, MAX_ATTACHMENTS_PER_MESSAGE, MAX_PAGE_SIZE, ReactionChange, ReadReceipt, RemovedMember, ServerEvent, Typing, PROTOCOL_VERSION};
+
+// How long a typing indicator stays up without a fresh typing_start
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+// A resumed connection gets at most this many pages of missed messages, the rest is fetched over HTTP
+const MAX_REPLAY_PAGES: usize = 10;
+// how far before last_seen_message_id a replay reaches back, for messages that committed out of id order
+const REPLAY_OVERLAP: Duration = Duration::from_secs(10);
+
+// Close codes, 4000 and up are ours, the rest come from RFC 6455
+const CLOSE_GOING_AWAY: u16 = 1001;
+// sent when a connection is dropped for not reading its events fast enough
+const CLOSE_SLOW_CONSUMER: u16 = 1008;
+// sent for a frame over MessageLimits::max_frame_size, the rest of it is never read
+const CLOSE_TOO_LARGE: u16 = 1009;
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+const CLOSE_BAD_REQUEST: u16 = 4000;
+// missing, invalid or expired token
+const CLOSE_UNAUTHORIZED: u16 = 4001;
+const CLOSE_FORBIDDEN: u16 = 4003;
+const CLOSE_GROUP_DELETED: u16 = 4004;
+const CLOSE_HEARTBEAT_TIMEOUT: u16 = 4008;
+// a slow consumer may never take the close frame, so don't wait on it for long
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Per-connection state, owned by the task reading from the socket
+struct Connection {
+    id: Uuid,
+    user_id: i32,
+    username: String,
+    // group used when an event omits group_id, only set for /ws/group/:group_id sockets
+    default_group: Option<i32>,
+    // which groups the connection is subscribed to, checked there so removing a member takes effect right away
+    channels: Arc<ChannelRegistry>,
+    joined: JoinedGroups,
+    tx: QueueSender,
+    // expiry timers for the groups this connection is typing in, a finished timer already sent typing_stop
+    typing: HashMap<i32, JoinHandle<()>>,
+}
+
+impl Connection {
+    fn send(&self, event: &ServerEvent) {
+        if self.tx.send(event.to_outbound()).is_err() {
+            eprintln!("Failed to send message to {}", self.id);
+        }
+    }
+
+    fn subscribe(&self, group_id: i32) {
+        self.joined.lock().unwrap().insert(group_id);
+        self.channels.subscribe(group_id, self.id, self.tx.clone());
+    }
+
+    fn unsubscribe(&self, group_id: i32) {
+        self.channels.unsubscribe(group_id, self.id);
+        self.joined.lock().unwrap().remove(&group_id);
+    }
+
+    // replies with an error event and returns None when the connection can't use the group
+    fn resolve_group(&self, group_id: Option<i32>) -> Option<i32> {
+        match group_id.or(self.default_group) {
+            Some(group_id) if self.channels.is_subscribed(group_id, self.id) => Some(group_id),
+            Some(group_id) => {
+                self.send(&ServerEvent::group_error(ErrorCode::NotSubscribed, group_id, "Not subscribed to this group"));
+                None
+            }
+            None => {
+                self.send(&ServerEvent::error(ErrorCode::MissingGroup, "Missing group_id"));
+                None
+            }
+        }
+    }
+
+    fn typing(&self, group_id: i32) -> Typing {
+        Typing { group_id, user_id: self.user_id, username: self.username.clone() }
+    }
+
+    // only the first typing_start is relayed, repeats just push the expiry back
+    async fn start_typing(&mut self, state: &Arc<ServerState>, group_id: i32) {
+        let already_typing = match self.typing.remove(&group_id) {
+            Some(timer) if !timer.is_finished() => {
+                timer.abort();
+                true
+            }
+            _ => false,
+        };
+
+        if !already_typing {
+            let event = ServerEvent::TypingStart(self.typing(group_id));
+            broadcast_to_others(state.clone(), group_id, self.id, &event).await;
+        }
+
+        let state = state.clone();
+        let connection_id = self.id;
+        let event = ServerEvent::TypingStop(self.typing(group_id));
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(TYPING_TIMEOUT).await;
+            broadcast_to_others(state, group_id, connection_id, &event).await;
+        });
+        self.typing.insert(group_id, timer);
+    }
+
+    async fn stop_typing(&mut self, state: &Arc<ServerState>, group_id: i32) {
+        if let Some(timer) = self.typing.remove(&group_id) {
+            if !timer.is_finished() {
+                timer.abort();
+                let event = ServerEvent::TypingStop(self.typing(group_id));
+                broadcast_to_others(state.clone(), group_id, self.id, &event).await;
+            }
+        }
+    }
+
+    async fn stop_all_typing(&mut self, state: &Arc<ServerState>) {
+        let group_ids = self.typing.keys().copied().collect::<Vec<_>>();
+        for group_id in group_ids {
+            self.stop_typing(state, group_id).await;
+        }
+    }
+}
+
+// Multiplexed socket, subscribed to every group the user is a member of
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    if state.shutdown.is_closing() {
+        return reject(ws, CLOSE_GOING_AWAY, "Server shutting down");
+    }
+
+    let (user_id, expires_at) = match authenticate(&params).await {
+        Ok(auth) => auth,
+        Err((code, reason)) => return reject(ws, code, reason),
+    };
+
+    // set by clients reconnecting after a drop, everything newer is replayed before live events
+    let last_seen = match params.get("last_seen_message_id").map(|id| id.parse::<i32>()).transpose() {
+        Ok(last_seen) => last_seen,
+        Err(_) => return reject(ws, CLOSE_BAD_REQUEST, "Invalid last_seen_message_id"),
+    };
+
+    let group_ids = match fetch_group_ids_for_user(user_id, &state.db).await {
+        Ok(group_ids) => group_ids,
+        Err(_) => return reject(ws, CLOSE_INTERNAL_ERROR, "Failed to fetch groups"),
+    };
+
+    // larger frames close the connection before they're read into memory
+    let max_frame_size = state.message_limits.max_frame_size();
+    let ws = ws.max_message_size(max_frame_size).max_frame_size(max_frame_size);
+    ws.on_upgrade(move |socket: WebSocket| handle_socket(socket, user_id, expires_at, state, group_ids, None, last_seen))
+}
+
+// Single group socket, kept for clients that open one connection per chat
+pub async fn group_ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<ServerState>>,
+    Path(group_id): Path<i32>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    if state.shutdown.is_closing() {
+        return reject(ws, CLOSE_GOING_AWAY, "Server shutting down");
+    }
+
+    let (user_id, expires_at) = match authenticate(&params).await {
+        Ok(auth) => auth,
+        Err((code, reason)) => return reject(ws, code, reason),
+    };
+
+    let last_seen = match params.get("last_seen_message_id").map(|id| id.parse::<i32>()).transpose() {
+        Ok(last_seen) => last_seen,
+        Err(_) => return reject(ws, CLOSE_BAD_REQUEST, "Invalid last_seen_message_id"),
+    };
+
+    let password = params.get("password").map(String::as_str);
+    if let Err((status, message)) = authorize_group(user_id, group_id, password, &state).await {
+        let code = match status {
+            StatusCode::INTERNAL_SERVER_ERROR => CLOSE_INTERNAL_ERROR,
+            _ => CLOSE_FORBIDDEN,
+        };
+        return reject(ws, code, message);
+    }
+
+    let max_frame_size = state.message_limits.max_frame_size();
+    let ws = ws.max_message_size(max_frame_size).max_frame_size(max_frame_size);
+    ws.on_upgrade(move |socket: WebSocket| handle_socket(socket, user_id, expires_at, state, vec![group_id], Some(group_id), last_seen))
+}
+
+// Browsers can't read the status of a failed handshake, so accept it and close right away with a code they can see
+fn reject(ws: WebSocketUpgrade, code: u16, reason: &'static str) -> Response {
+    ws.on_upgrade(move |mut socket: WebSocket| async move {
+        close_socket(&mut socket, code, reason).await;
+    })
+}
+
+async fn close_socket<S>(sender: &mut S, code: u16, reason: &'static str)
+where
+    S: SinkExt<Message> + Unpin,
+{
+    let close = Message::Close(Some(CloseFrame { code, reason: reason.into() }));
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, sender.send(close)).await;
+}
+
+// user id and token expiry, or the close code to reject the socket with
+async fn authenticate(params: &HashMap<String, String>) -> Result<(i32, u64), (u16, &'static str)> {
+    let token = match params.get("token") {
+        Some(token) => token,
+        None => return Err((CLOSE_UNAUTHORIZED, "Missing authentication token")),
+    };
+    dotenv().ok();
+    let key = std::env::var("JWT_KEY").expect("Must set JWT_KEY environment variable");
+
+    match validate_token(token, key).await {
+        Ok(claims) => Ok((claims.sub.parse::<i32>().unwrap(), claims.exp)),
+        Err(_) => Err((CLOSE_UNAUTHORIZED, "Invalid token")),
+    }
+}
+
+fn is_expired(expires_at: u64) -> bool {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    now >= expires_at
+}
+
+// Temp chats are open to anyone with the password, every other group requires membership
+async fn authorize_group(user_id: i32, group_id: i32, password: Option<&str>, state: &Arc<ServerState>)
+-> Result<(), (StatusCode, &'static str)> {
+    let db = &state.db;
+    let group_type = match fetch_group_type(group_id, db).await {
+        Ok(group_type) => group_type,
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch group type")),
+    };
+
+    if group_type == 3 {
+        let temp_chat_info = match get_temp_info_with_group_id(group_id, db).await {
+            Ok(temp_chat_info) => temp_chat_info,
+            Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")),
+        };
+
+        if check_end_date(temp_chat_info.end_date, group_id, state).await.is_err() {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"));
+        }
+
+        let password = match password {
+            Some(password) => password,
+            None => return Err((StatusCode::UNAUTHORIZED, "Missing password")),
+        };
+        if !(verify(password, &temp_chat_info.password).unwrap_or(false)) {
+            return Err((StatusCode::UNAUTHORIZED, "Invalid password"));
+        }
+
+        return Ok(());
+    }
+
+    match is_user_in_group(user_id, group_id, db).await {
+        Ok(_) => Ok(()),
+        Err(_) => Err((StatusCode::UNAUTHORIZED, "Unauthorized")),
+    }
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    user_id: i32,
+    expires_at: u64,
+    state: Arc<ServerState>,
+    mut group_ids: Vec<i32>,
+    default_group: Option<i32>,
+    last_seen: Option<i32>,
+) {
+    let connection_id = Uuid::new_v4();
+    // held until the connection is cleaned up, so shutdown waits for it
+    let _tracked = state.shutdown.track();
+
+    let username = match fetch_username(user_id, &state.db).await {
+        Ok(username) => username,
+        Err(e) => {
+            eprintln!("Failed to fetch username for {}: {}", user_id, e);
+            return;
+        }
+    };
+
+    let (mut sender, mut receiver) = socket.split();
+    let (queue_tx, mut queue_rx) = queue::channel(state.queue_config, state.queue_stats.clone());
+
+    for group_id in group_ids.iter() {
+        state.channels.subscribe(*group_id, connection_id, queue_tx.clone());
+    }
+    let joined: JoinedGroups = Arc::new(Mutex::new(group_ids.iter().copied().collect()));
+    presence::connect(&state, user_id, connection_id, queue_tx.clone(), joined.clone()).await;
+
+    // a removal that committed after the handshake checked membership may have swept the channels before this
+    // connection was in them, now that it is any later removal will find it
+    match fetch_lost_group_ids(user_id, &group_ids, &state.db).await {
+        Ok(lost) => {
+            for group_id in lost {
+                state.channels.unsubscribe(group_id, connection_id);
+                joined.lock().unwrap().remove(&group_id);
+                group_ids.retain(|id| *id != group_id);
+                // closes a single group socket the same way a live removal does
+                if Some(group_id) == default_group {
+                    let removed = ServerEvent::MemberRemoved(RemovedMember { group_id, user_id });
+                    let _ = queue_tx.send(removed.to_outbound());
+                }
+            }
+        }
+        Err(e) => eprintln!("Failed to recheck groups for connection {}: {}", connection_id, e),
+    }
+
+    let mut connection = Connection {
+        id: connection_id,
+        user_id,
+        username,
+        default_group,
+        channels: state.channels.clone(),
+        joined: joined.clone(),
+        tx: queue_tx,
+        typing: HashMap::new(),
+    };
+
+    let liveness = Arc::new(Liveness::default());
+    let heartbeat = state.heartbeat;
+    // lets the receiving side have the connection closed with a reason, only the sending side can write
+    let (close_tx, mut close_rx) = mpsc::channel::<(u16, &'static str)>(1);
+
+    // Task to broadcast messages to this client
+    let replay_state = state.clone();
+    let send_liveness = liveness.clone();
+    let send_shutdown = state.shutdown.clone();
+    let mut send_task = tokio::spawn(async move {
+        if default_group.is_none() {
+            let ready = ServerEvent::Ready { group_ids: group_ids.clone() };
+            if sender.send(ready.to_message()).await.is_err() {
+                return;
+            }
+        }
+
+        // the connection is already registered, so anything broadcast while replaying waits in the queue
+        let replayed = match last_seen {
+            Some(last_seen) => tokio::select! {
+                replayed = replay_missed(&mut sender, &replay_state, user_id, &group_ids, last_seen) => replayed,
+                _ = queue_rx.evicted() => None,
+            },
+            None => Some(HashSet::new()),
+        };
+
+        let mut close = None;
+        if let Some(replayed) = replayed {
+            let mut ping = tokio::time::interval_at(Instant::now() + heartbeat.ping_interval, heartbeat.ping_interval);
+            // when the oldest ping still waiting on the peer went out
+            let mut awaiting_pong: Option<Instant> = None;
+
+            close = loop {
+                tokio::select! {
+                    outbound = queue_rx.recv() => {
+                        let Some(outbound) = outbound else { break None };
+                        if outbound.message_id.is_some_and(|id| replayed.contains(&id)) {
+                            continue;
+                        }
+                        // a single group socket has nothing left to show once its group is gone or the user left it
+                        let group_deleted = outbound.deleted_group.is_some() && outbound.deleted_group == default_group;
+                        let removed = outbound
+                            .removed_member
+                            .is_some_and(|removed| removed.user_id == user_id && Some(removed.group_id) == default_group);
+                        tokio::select! {
+                            result = sender.send(outbound.frame) => {
+                                if result.is_err() {
+                                    eprintln!("error trying to send message, connection_id: {}", connection_id);
+                                    break None;
+                                }
+                            }
+                            _ = queue_rx.evicted() => break None,
+                        }
+                        if group_deleted {
+                            break Some((CLOSE_GROUP_DELETED, "Group deleted"));
+                        }
+                        if removed {
+                            break Some((CLOSE_FORBIDDEN, "No longer a member of this group"));
+                        }
+                    }
+                    _ = send_shutdown.closing() => break Some((CLOSE_GOING_AWAY, "Server shutting down")),
+                    Some(close) = close_rx.recv() => break Some(close),
+                    _ = ping.tick() => {
+                        if is_expired(expires_at) {
+                            break Some((CLOSE_UNAUTHORIZED, "Token expired"));
+                        }
+                        if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                            break None;
+                        }
+                        awaiting_pong.get_or_insert_with(Instant::now);
+                    }
+                    _ = tokio::time::sleep_until(awaiting_pong.unwrap_or_else(Instant::now) + heartbeat.pong_timeout), if awaiting_pong.is_some() => {
+                        if !send_liveness.heard_since(awaiting_pong.unwrap().into_std()) {
+                            eprintln!("No pong from connection {}, dropping it", connection_id);
+                            break Some((CLOSE_HEARTBEAT_TIMEOUT, "Heartbeat timeout"));
+                        }
+                        awaiting_pong = None;
+                    }
+                }
+            };
+        }
+
+        if queue_rx.is_evicted() {
+            eprintln!("Dropping slow connection, connection_id: {}", connection_id);
+            close = Some((CLOSE_SLOW_CONSUMER, "Too slow to keep up with events"));
+        }
+        if let Some((code, reason)) = close {
+            close_socket(&mut sender, code, reason).await;
+        }
+    });
+
+    let state_clone = state.clone();
+    let recv_shutdown = state.shutdown.clone();
+    // Task to handle messages from this client
+    let mut recv_task = tokio::spawn(async move {
+        let mut closing = false;
+        loop {
+            // stop between frames on shutdown, so a message being stored isn't cut off halfway
+            let result = tokio::select! {
+                result = receiver.next() => result,
+                _ = recv_shutdown.closing() => break,
+            };
+            let Some(result) = result else { break };
+
+            match result {
+                Ok(msg) => {
+                    // any frame shows the peer is still there, pongs included
+                    liveness.touch();
+                    match msg {
+                        Message::Text(text_content) => {
+                            handle_client_frame(&text_content, &mut connection, &state_clone).await;
+                        }
+                        Message::Binary(_) => {
+                            connection.send(&ServerEvent::error(ErrorCode::UnsupportedFrame, "Binary frames are not supported"));
+                        }
+                        Message::Close(_) => {}
+                        _ =>{}
+                    }
+                }
+                Err(e) if is_too_large(&e) => {
+                    // the rest of the frame is still unread, so nothing after it can be trusted
+                    closing = close_tx.try_send((CLOSE_TOO_LARGE, "Frame too large")).is_ok();
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Error receiving message, connection_id: {}, {:?}", connection_id, e)
+                }
+            }
+        }
+
+        // if this task gets aborted instead, the pending timers still send typing_stop on expiry
+        connection.stop_all_typing(&state_clone).await;
+        closing
+    });
+
+    // Wait for either task to complete, then stop the other one. On shutdown both stop by themselves,
+    // so let the other one finish its write instead
+    tokio::select! {
+        _ = &mut send_task => {
+            if state.shutdown.is_closing() {
+                let _ = recv_task.await;
+            } else {
+                recv_task.abort();
+            }
+        }
+        closing = &mut recv_task => {
+            // the sending side still has a close frame to write
+            if state.shutdown.is_closing() || closing.unwrap_or(false) {
+                let _ = send_task.await;
+            } else {
+                send_task.abort();
+            }
+        }
+    }
+
+    //clean up channels this connection is still subscribed to
+    let joined = std::mem::take(&mut *joined.lock().unwrap());
+    state.channels.remove_connection(connection_id, joined);
+    presence::disconnect(&state, user_id, connection_id).await;
+}
+
+fn is_too_large(e: &axum::Error) -> bool {
+    matches!(
+        std::error::Error::source(e).and_then(|e| e.downcast_ref::<tungstenite::Error>()),
+        Some(tungstenite::Error::Capacity(_))
+    )
+}
+
+fn rate_limited(group_id: i32, limited: &Limited) -> ServerEvent {
+    let message = match limited.scope {
+        LimitScope::User => "You are sending messages too fast",
+        LimitScope::Group => "This group is receiving too many messages",
+    };
+    ServerEvent::rate_limited(group_id, message, limited.retry_after)
+}
+
+// parses a single text frame and dispatches it, replying to the sender with an error event on failure
+async fn handle_client_frame(text: &str, connection: &mut Connection, state: &Arc<ServerState>) {
+    let frame = match serde_json::from_str::<ClientFrame>(text) {
+        Ok(frame) => frame,
+        Err(e) => {
+            connection.send(&ServerEvent::error(ErrorCode::MalformedFrame, e.to_string()));
+            return;
+        }
+    };
+
+    if frame.v != PROTOCOL_VERSION {
+        connection.send(&ServerEvent::error(
+            ErrorCode::UnsupportedVersion,
+            format!("Unsupported protocol version {}, expected {}", frame.v, PROTOCOL_VERSION),
+        ));
+        return;
+    }
+
+    match frame.event {
+        ClientEvent::Send { group_id, content, reply_to, mut attachment_ids, nonce } => {
+            let Some(group_id) = connection.resolve_group(group_id) else {
+                return;
+            };
+            attachment_ids.sort_unstable();
+            attachment_ids.dedup();
+            if attachment_ids.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+                return connection.send(&ServerEvent::group_error(
+                    ErrorCode::InvalidContent,
+                    group_id,
+                    format!("A message can have at most {} attachments", MAX_ATTACHMENTS_PER_MESSAGE),
+                ));
+            }
+            let content = match state.message_limits.check_content(&content, !attachment_ids.is_empty()) {
+                Ok(content) => content,
+                Err(message) => return connection.send(&ServerEvent::group_error(ErrorCode::InvalidContent, group_id, message)),
+            };
+            match can_post_in_group(connection.user_id, group_id, &state.db).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return connection.send(&ServerEvent::group_error(ErrorCode::Forbidden, group_id, "Only admins can post in this group"));
+                }
+                Err(e) => {
+                    eprintln!("Failed to fetch group settings: {}", e);
+                    return connection.send(&ServerEvent::group_error(ErrorCode::InternalError, group_id, "Failed to store message"));
+                }
+            }
+            if let Err(limited) = state.rate_limiter.check(connection.user_id, group_id) {
+                return connection.send(&rate_limited(group_id, &limited));
+            }
+
+            if let Some(reply_to) = reply_to {
+                match fetch_message_ref(reply_to, &state.db).await {
+                    Ok(parent) if parent.group_id == group_id => {}
+                    Ok(_) | Err(sqlx::Error::RowNotFound) => {
+                        return connection.send(&ServerEvent::group_error(
+                            ErrorCode::NotFound,
+                            group_id,
+                            "Replied to message not found in this group",
+                        ));
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to fetch message: {}", e);
+                        return connection.send(&ServerEvent::group_error(ErrorCode::InternalError, group_id, "Failed to store message"));
+                    }
+                }
+            }
+
+            match insert_message_in_db(connection.user_id, group_id, content, reply_to, &attachment_ids, &state.db).await {
+                Ok(record) => {
+                    connection.send(&ServerEvent::Ack { group_id, nonce, message_id: record.id });
+                    connection.stop_typing(state, group_id).await;
+
+                    broadcast_message(state.clone(), group_id, &ServerEvent::Message(record)).await;
+                }
+                Err(sqlx::Error::RowNotFound) => {
+                    connection.send(&ServerEvent::group_error(ErrorCode::NotFound, group_id, "Attachment not found in this group"));
+                }
+                Err(e) => {
+                    eprintln!("Failed to store message: {}", e);
+                    connection.send(&ServerEvent::group_error(ErrorCode::InternalError, group_id, "Failed to store message"));
+                }
+            }
+        }
+        ClientEvent::TypingStart { group_id } => {
+            let Some(group_id) = connection.resolve_group(group_id) else {
+                return;
+            };
+            connection.start_typing(state, group_id).await;
+        }
+        ClientEvent::TypingStop { group_id } => {
+            let Some(group_id) = connection.resolve_group(group_id) else {
+                return;
+            };
+            connection.stop_typing(state, group_id).await;
+        }
+        ClientEvent::Read { group_id, message_id } => {
+            let Some(group_id) = connection.resolve_group(group_id) else {
+                return;
+            };
+
+            match fetch_message_ref(message_id, &state.db).await {
+                Ok(message) if message.group_id == group_id => {}
+                Ok(_) | Err(sqlx::Error::RowNotFound) => {
+                    return connection.send(&ServerEvent::group_error(ErrorCode::NotFound, group_id, "Message not found in this group"));
+                }
+                Err(e) => {
+                    eprintln!("Failed to fetch message: {}", e);
+                    return connection.send(&ServerEvent::group_error(ErrorCode::InternalError, group_id, "Failed to mark as read"));
+                }
+            }
+
+            match mark_read(connection.user_id, group_id, message_id, &state.db).await {
+                Ok(Some(message_id)) => {
+                    let receipt = ReadReceipt { group_id, user_id: connection.user_id, message_id };
+                    broadcast_message(state.clone(), group_id, &ServerEvent::ReadReceipt(receipt)).await;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("Failed to mark as read: {}", e);
+                    connection.send(&ServerEvent::group_error(ErrorCode::InternalError, group_id, "Failed to mark as read"));
+                }
+            }
+        }
+        ClientEvent::Idle => {
+            presence::set_idle(state, connection.user_id, connection.id, true).await;
+        }
+        ClientEvent::Active => {
+            presence::set_idle(state, connection.user_id, connection.id, false).await;
+        }
+        ClientEvent::Subscribe { group_id, password } => {
+            if !state.channels.is_subscribed(group_id, connection.id) {
+                if let Err((_, message)) = authorize_group(connection.user_id, group_id, password.as_deref(), state).await {
+                    return connection.send(&ServerEvent::group_error(ErrorCode::Forbidden, group_id, message));
+                }
+
+                connection.subscribe(group_id);
+                // checked again once subscribed, a removal committing in between has already swept the channel
+                if !matches!(fetch_lost_group_ids(connection.user_id, &[group_id], &state.db).await, Ok(lost) if lost.is_empty()) {
+                    connection.unsubscribe(group_id);
+                    return connection.send(&ServerEvent::group_error(ErrorCode::Forbidden, group_id, "Unauthorized"));
+                }
+            }
+            connection.send(&ServerEvent::Subscribed { group_id });
+        }
+        ClientEvent::Unsubscribe { group_id } => {
+            connection.stop_typing(state, group_id).await;
+            connection.unsubscribe(group_id);
+            connection.send(&ServerEvent::Unsubscribed { group_id });
+        }
+        ClientEvent::Edit { message_id, content } => {
+            let Some(message) = fetch_subscribed_message(connection, state, message_id).await else {
+                return;
+            };
+            if message.system {
+                return connection.send(&ServerEvent::group_error(
+                    ErrorCode::Forbidden,
+                    message.group_id,
+                    "System messages cannot be edited",
+                ));
+            }
+            if message.user_id != connection.user_id {
+                return connection.send(&ServerEvent::group_error(
+                    ErrorCode::Forbidden,
+                    message.group_id,
+                    "Only the author can edit a message",
+                ));
+            }
+            let content = match state.message_limits.check_content(&content, false) {
+                Ok(content) => content,
+                Err(error) => return connection.send(&ServerEvent::group_error(ErrorCode::InvalidContent, message.group_id, error)),
+            };
+            if let Err(limited) = state.rate_limiter.check(connection.user_id, message.group_id) {
+                return connection.send(&rate_limited(message.group_id, &limited));
+            }
+
+            match edit_message(message_id, content, &state.db).await {
+                Ok(edit) => {
+                    broadcast_message(state.clone(), edit.group_id, &ServerEvent::MessageEdited(edit)).await;
+                }
+                Err(sqlx::Error::RowNotFound) => {
+                    connection.send(&ServerEvent::group_error(ErrorCode::NotFound, message.group_id, "Message not found"));
+                }
+                Err(e) => {
+                    eprintln!("Failed to edit message: {}", e);
+                    connection.send(&ServerEvent::group_error(ErrorCode::InternalError, message.group_id, "Failed to edit message"));
+                }
+            }
+        }
+        ClientEvent::Delete { message_id } => {
+            let Some(message) = fetch_subscribed_message(connection, state, message_id).await else {
+                return;
+            };
+            if message.system {
+                return connection.send(&ServerEvent::group_error(
+                    ErrorCode::Forbidden,
+                    message.group_id,
+                    "System messages cannot be deleted",
+                ));
+            }
+            match can_delete_message(connection.user_id, &message, &state.db).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return connection.send(&ServerEvent::group_error(
+                        ErrorCode::Forbidden,
+                        message.group_id,
+                        "Only the author or the group owner can delete a message",
+                    ));
+                }
+                Err(e) => {
+                    eprintln!("Failed to fetch group owner: {}", e);
+                    return connection.send(&ServerEvent::group_error(ErrorCode::InternalError, message.group_id, "Failed to delete message"));
+                }
+            }
+
+            match delete_message(message_id, &state.db).await {
+                Ok(deletion) => {
+                    delete_files(state.storage.as_ref(), &deletion.attachment_keys).await;
+                    broadcast_message(state.clone(), deletion.group_id, &ServerEvent::MessageDeleted(deletion)).await;
+                }
+                Err(e) => {
+                    eprintln!("Failed to delete message: {}", e);
+                    connection.send(&ServerEvent::group_error(ErrorCode::InternalError, message.group_id, "Failed to delete message"));
+                }
+            }
+        }
+        ClientEvent::React { message_id, emoji } => {
+            handle_reaction(connection, state, message_id, emoji, true).await;
+        }
+        ClientEvent::Unreact { message_id, emoji } => {
+            handle_reaction(connection, state, message_id, emoji, false).await;
+        }
+    }
+}
+
+// looks up a message that hasn't been deleted in one of the connection's groups, replying with an error otherwise
+async fn fetch_subscribed_message(connection: &Connection, state: &Arc<ServerState>, message_id: i32) -> Option<MessageRef> {
+    let message = match fetch_message_ref(message_id, &state.db).await {
+        Ok(message) if !message.deleted => message,
+        Ok(_) | Err(sqlx::Error::RowNotFound) => {
+            connection.send(&ServerEvent::error(ErrorCode::NotFound, "Message not found"));
+            return None;
+        }
+        Err(e) => {
+            eprintln!("Failed to fetch message: {}", e);
+            connection.send(&ServerEvent::error(ErrorCode::InternalError, "Failed to fetch message"));
+            return None;
+        }
+    };
+
+    connection.resolve_group(Some(message.group_id))?;
+    Some(message)
+}
+
+async fn handle_reaction(connection: &Connection, state: &Arc<ServerState>, message_id: i32, emoji: String, added: bool) {
+    let emoji = match check_emoji(&emoji) {
+        Ok(emoji) => emoji,
+        Err(error) => return connection.send(&ServerEvent::error(ErrorCode::InvalidContent, error)),
+    };
+
+    let Some(message) = fetch_subscribed_message(connection, state, message_id).await else {
+        return;
+    };
+
+    if is_user_in_group(connection.user_id, message.group_id, &state.db).await.is_err() {
+        return connection.send(&ServerEvent::group_error(ErrorCode::Forbidden, message.group_id, "User not in group"));
+    }
+
+    let result = if added {
+        add_reaction(message_id, connection.user_id, emoji, &state.db).await
+    } else {
+        remove_reaction(message_id, connection.user_id, emoji, &state.db).await
+    };
+
+    let count = match result {
+        Ok(_) => fetch_reaction_count(message_id, emoji, &state.db).await,
+        Err(e) => Err(e),
+    };
+    let count = match count {
+        Ok(count) => count,
+        Err(e) => {
+            eprintln!("Failed to update reaction: {}", e);
+            return connection.send(&ServerEvent::group_error(ErrorCode::InternalError, message.group_id, "Failed to update reaction"));
+        }
+    };
+
+    let change = ReactionChange {
+        message_id,
+        group_id: message.group_id,
+        user_id: connection.user_id,
+        emoji: emoji.to_string(),
+        count,
+    };
+    let event = if added { ServerEvent::ReactionAdded(change) } else { ServerEvent::ReactionRemoved(change) };
+    broadcast_message(state.clone(), message.group_id, &event).await;
+}
+
+// Sends the messages newer than last_seen straight to the socket, then a `resumed` event, or `resume_truncated`
+// when there were more than MAX_REPLAY_PAGES pages. The few messages stored just before last_seen are sent again,
+// see fetch_replay_start, clients skip ids they already have.
+// Returns the ids that were sent, so the same messages coming in live aren't sent twice
+async fn replay_missed(
+    sender: &mut SplitSink<WebSocket, Message>,
+    state: &Arc<ServerState>,
+    user_id: i32,
+    group_ids: &[i32],
+    last_seen: i32,
+) -> Option<HashSet<i32>> {
+    let mut replayed = HashSet::new();
+    let mut last_message_id = None;
+    let mut truncated = true;
+
+    let start = fetch_replay_start(group_ids, last_seen, REPLAY_OVERLAP.as_secs_f64(), &state.db).await;
+    let mut after = match start {
+        Ok(start) => start,
+        Err(e) => return replay_failed(sender, e).await,
+    };
+
+    for _ in 0..MAX_REPLAY_PAGES {
+        // one extra row tells whether there's another page
+        let mut messages = match fetch_messages_since(group_ids, after, user_id, MAX_PAGE_SIZE + 1, &state.db).await {
+            Ok(messages) => messages,
+            Err(e) => return replay_failed(sender, e).await,
+        };
+        let has_more = messages.len() as i64 > MAX_PAGE_SIZE;
+        messages.truncate(MAX_PAGE_SIZE as usize);
+
+        for message in messages {
+            after = message.id;
+            last_message_id = Some(message.id);
+            replayed.insert(message.id);
+            sender.send(ServerEvent::Message(message).to_message()).await.ok()?;
+        }
+        if !has_more {
+            truncated = false;
+            break;
+        }
+    }
+
+    let done = if truncated {
+        ServerEvent::ResumeTruncated { replayed: replayed.len(), last_message_id }
+    } else {
+        ServerEvent::Resumed { replayed: replayed.len(), last_message_id }
+    };
+    sender.send(done.to_message()).await.ok()?;
+    Some(replayed)
+}
+
+async fn replay_failed(sender: &mut SplitSink<WebSocket, Message>, e: sqlx::Error) -> Option<HashSet<i32>> {
+    eprintln!("Failed to fetch missed messages: {}", e);
+    let error = ServerEvent::error(ErrorCode::InternalError, "Failed to replay missed messages");
+    let _ = sender.send(error.to_message()).await;
+    None
+}
+
+//helper function to broadcast messages
+pub async fn broadcast_message(
+    state: Arc<ServerState>,
+    group_id: i32,
+    event: &ServerEvent,
+) {
+    send_to_group(state, group_id, None, event).await;
+}
+
+// same as broadcast_message but skips the connection the event came from
+async fn broadcast_to_others(
+    state: Arc<ServerState>,
+    group_id: i32,
+    connection_id: Uuid,
+    event: &ServerEvent,
+) {
+    send_to_group(state, group_id, Some(connection_id), event).await;
+}
+
+async fn send_to_group(
+    state: Arc<ServerState>,
+    group_id: i32,
+    skip: Option<Uuid>,
+    event: &ServerEvent,
+) {
+    let audience = Audience { groups: vec![group_id], skip_connection: skip, ..Default::default() };
+    fanout::send(&state, audience, event).await;
+}
+
+// Counters for the per-connection send queues
+// Only for whoever runs the server, with ?token= set to STATS_TOKEN. Without STATS_TOKEN it's turned off
+pub async fn queue_stats(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let Ok(stats_token) = std::env::var("STATS_TOKEN") else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // compared as hashes so the time taken doesn't give away how much of the token matched
+    let given = params.get("token").map(|token| Sha256::digest(token.as_bytes()));
+    if given != Some(Sha256::digest(stats_token.as_bytes())) {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Invalid token" }))).into_response();
+    }
+
+    Json(json!({
+        "queue_capacity": state.queue_config.capacity,
+        "slow_consumer_policy": state.queue_config.policy,
+        "dropped_events": state.queue_stats.dropped_events.load(Ordering::Relaxed),
+        "evicted_connections": state.queue_stats.evicted_connections.load(Ordering::Relaxed),
+        "dropped_relay_events": state.fanout.dropped_events(),
+    })).into_response()
+}
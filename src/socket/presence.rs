@@ -0,0 +1,190 @@
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::socket::fanout::{self, Audience, INSTANCE_TIMEOUT};
+use crate::socket::queue::QueueSender;
+use crate::socket::registry::JoinedGroups;
+use crate::state::{ServerState, UserConnection, UserMap};
+use crate::utils::queries::{
+    fetch_friends_for_user, fetch_presence_statuses, fetch_private_group_ids_for_user, fetch_visible_user_ids, remove_instances,
+    set_instance_presence,
+};
+use crate::utils::types::{Presence, ServerEvent, UserPresence};
+
+// Sent to the presence writer when a user's status on this instance may have changed
+pub enum PresenceUpdate {
+    User(i32),
+    // this instance's rows were dropped while it was unreachable, write them all again
+    Resync,
+}
+
+fn presence_of(users: &UserMap, user_id: i32) -> Presence {
+    match users.get(&user_id) {
+        None => Presence::Offline,
+        Some(connections) if connections.values().any(|c| !c.idle) => Presence::Online,
+        Some(_) => Presence::Idle,
+    }
+}
+
+// users the viewer has no friendship or group with always show as offline, so presence can't be probed for anyone
+pub async fn fetch_presence(state: &Arc<ServerState>, viewer_id: i32, user_ids: &[i32]) -> Result<Vec<UserPresence>, sqlx::Error> {
+    let visible = fetch_visible_user_ids(viewer_id, user_ids, &state.db).await?;
+    let statuses = fetch_presence_statuses(&visible, None, INSTANCE_TIMEOUT.as_secs_f64(), &state.db)
+        .await?
+        .into_iter()
+        .map(|presence| (presence.user_id, presence.status))
+        .collect::<HashMap<_, _>>();
+    Ok(user_ids
+        .iter()
+        .map(|&user_id| UserPresence { user_id, status: statuses.get(&user_id).copied().unwrap_or(Presence::Offline) })
+        .collect())
+}
+
+pub async fn connect(state: &Arc<ServerState>, user_id: i32, connection_id: Uuid, tx: QueueSender, joined: JoinedGroups) {
+    update(state, user_id, |users| {
+        users.entry(user_id).or_default().insert(connection_id, UserConnection { tx, idle: false, joined });
+    })
+    .await;
+}
+
+pub async fn disconnect(state: &Arc<ServerState>, user_id: i32, connection_id: Uuid) {
+    update(state, user_id, |users| {
+        if let Some(connections) = users.get_mut(&user_id) {
+            connections.remove(&connection_id);
+
+            if connections.is_empty() {
+                users.remove(&user_id);
+            }
+        }
+    })
+    .await;
+}
+
+pub async fn set_idle(state: &Arc<ServerState>, user_id: i32, connection_id: Uuid, idle: bool) {
+    update(state, user_id, |users| {
+        if let Some(connection) = users.get_mut(&user_id).and_then(|c| c.get_mut(&connection_id)) {
+            connection.idle = idle;
+        }
+    })
+    .await;
+}
+
+// applies the change and hands the user to the presence writer if their status here moved
+async fn update(state: &Arc<ServerState>, user_id: i32, change: impl FnOnce(&mut UserMap)) {
+    let changed = {
+        let mut users = state.users.lock().await;
+        let before = presence_of(&users, user_id);
+        change(&mut users);
+        before != presence_of(&users, user_id)
+    };
+
+    if changed {
+        let _ = state.presence_updates.send(PresenceUpdate::User(user_id));
+    }
+}
+
+// Records this instance's view of each user in instance_presence, one at a time so the writes land in order,
+// and tells everyone who can see the user when their status across all instances moved
+pub async fn write(state: Arc<ServerState>, mut updates: mpsc::UnboundedReceiver<PresenceUpdate>) {
+    // what's in instance_presence for this instance, a missing user is offline
+    let mut recorded = HashMap::new();
+    while let Some(update) = updates.recv().await {
+        match update {
+            PresenceUpdate::User(user_id) => record(&state, &mut recorded, user_id).await,
+            PresenceUpdate::Resync => {
+                recorded.clear();
+                let user_ids = state.users.lock().await.keys().copied().collect::<Vec<_>>();
+                for user_id in user_ids {
+                    record(&state, &mut recorded, user_id).await;
+                }
+            }
+        }
+    }
+}
+
+async fn record(state: &Arc<ServerState>, recorded: &mut HashMap<i32, Presence>, user_id: i32) {
+    let instance_id = state.fanout.instance_id();
+    let local = presence_of(&*state.users.lock().await, user_id);
+    let previous = recorded.get(&user_id).copied().unwrap_or(Presence::Offline);
+    // several changes can be queued for the same user, only the latest one matters
+    if local == previous {
+        return;
+    }
+
+    if let Err(e) = set_instance_presence(instance_id, user_id, local, &state.db).await {
+        eprintln!("Failed to record presence for {}: {}", user_id, e);
+        return;
+    }
+    if local == Presence::Offline {
+        recorded.remove(&user_id);
+    } else {
+        recorded.insert(user_id, local);
+    }
+
+    // read after the write, so when two instances change the same user at once at least one sees both
+    let elsewhere = match fetch_presence_statuses(&[user_id], Some(instance_id), INSTANCE_TIMEOUT.as_secs_f64(), &state.db).await {
+        Ok(statuses) => statuses.first().map(|p| p.status).unwrap_or(Presence::Offline),
+        Err(e) => {
+            eprintln!("Failed to fetch presence for {}: {}", user_id, e);
+            return;
+        }
+    };
+    let (before, after) = (previous.max(elsewhere), local.max(elsewhere));
+    if before != after {
+        broadcast_presence(state, user_id, after).await;
+    }
+}
+
+// the users an instance had when it went away, announced for whoever isn't connected anywhere else
+pub async fn instances_lost(state: &Arc<ServerState>, lost: Vec<UserPresence>) {
+    if lost.is_empty() {
+        return;
+    }
+
+    let user_ids = lost.iter().map(|p| p.user_id).collect::<Vec<_>>();
+    let remaining = match fetch_presence_statuses(&user_ids, None, INSTANCE_TIMEOUT.as_secs_f64(), &state.db).await {
+        Ok(statuses) => statuses.into_iter().map(|p| (p.user_id, p.status)).collect::<HashMap<_, _>>(),
+        Err(e) => {
+            eprintln!("Failed to fetch presence: {}", e);
+            return;
+        }
+    };
+    for presence in lost {
+        let after = remaining.get(&presence.user_id).copied().unwrap_or(Presence::Offline);
+        if presence.status.max(after) != after {
+            broadcast_presence(state, presence.user_id, after).await;
+        }
+    }
+}
+
+// drops this instance's presence on shutdown instead of leaving it to time out
+pub async fn stop(state: &Arc<ServerState>) {
+    match remove_instances(Some(state.fanout.instance_id()), INSTANCE_TIMEOUT.as_secs_f64(), &state.db).await {
+        Ok(lost) => instances_lost(state, lost).await,
+        Err(e) => eprintln!("Failed to remove the server instance: {}", e),
+    }
+}
+
+// sends to the user's friends and to every connection in a group or DM the user is in, once per connection
+async fn broadcast_presence(state: &Arc<ServerState>, user_id: i32, status: Presence) {
+    let friend_ids = match fetch_friends_for_user(user_id, &state.db).await {
+        Ok(friends) => friends.iter().map(|f| f.id).collect::<Vec<_>>(),
+        Err(e) => {
+            eprintln!("Failed to fetch friends for presence: {}", e);
+            Vec::new()
+        }
+    };
+    let group_ids = match fetch_private_group_ids_for_user(user_id, &state.db).await {
+        Ok(group_ids) => group_ids,
+        Err(e) => {
+            eprintln!("Failed to fetch groups for presence: {}", e);
+            Vec::new()
+        }
+    };
+
+    let audience = Audience { groups: group_ids, users: friend_ids, skip_user: Some(user_id), ..Default::default() };
+    fanout::send(state, audience, &ServerEvent::Presence(UserPresence { user_id, status })).await;
+}
@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::utils::types::Outbound;
+
+const DEFAULT_CAPACITY: usize = 256;
+
+// What happens when a connection's queue is full
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    // close the connection, the client can reconnect and resume
+    Disconnect,
+    // keep the connection and lose the oldest queued events, other than control events
+    DropOldest,
+}
+
+#[derive(Clone, Copy)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub policy: SlowConsumerPolicy,
+}
+
+impl QueueConfig {
+    // WS_QUEUE_CAPACITY and WS_SLOW_CONSUMER_POLICY (disconnect or drop_oldest)
+    pub fn from_env() -> Self {
+        let capacity = match std::env::var("WS_QUEUE_CAPACITY") {
+            Ok(capacity) => capacity.parse::<usize>().ok().filter(|c| *c > 0).expect("WS_QUEUE_CAPACITY must be a positive number"),
+            Err(_) => DEFAULT_CAPACITY,
+        };
+        let policy = match std::env::var("WS_SLOW_CONSUMER_POLICY").as_deref() {
+            Err(_) | Ok("disconnect") => SlowConsumerPolicy::Disconnect,
+            Ok("drop_oldest") => SlowConsumerPolicy::DropOldest,
+            Ok(_) => panic!("WS_SLOW_CONSUMER_POLICY must be disconnect or drop_oldest"),
+        };
+
+        QueueConfig { capacity, policy }
+    }
+}
+
+#[derive(Default)]
+pub struct QueueStats {
+    pub dropped_events: AtomicU64,
+    pub evicted_connections: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct SendError;
+
+struct Items {
+    queue: VecDeque<Outbound>,
+    closed: bool,
+}
+
+struct Shared {
+    items: Mutex<Items>,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    stats: Arc<QueueStats>,
+    // wakes the receiver for new items and on close
+    available: Notify,
+    evicted: AtomicBool,
+    // wakes the receiver if it's stuck writing to the socket when the connection gets evicted
+    eviction: Notify,
+}
+
+// Per-connection send queue, bounded unlike mpsc::unbounded_channel so a stalled client can't grow memory forever
+pub fn channel(config: QueueConfig, stats: Arc<QueueStats>) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        items: Mutex::new(Items { queue: VecDeque::with_capacity(config.capacity), closed: false }),
+        capacity: config.capacity,
+        policy: config.policy,
+        stats,
+        available: Notify::new(),
+        evicted: AtomicBool::new(false),
+        eviction: Notify::new(),
+    });
+
+    (QueueSender(shared.clone()), QueueReceiver(shared))
+}
+
+#[derive(Clone)]
+pub struct QueueSender(Arc<Shared>);
+
+impl QueueSender {
+    pub fn send(&self, outbound: Outbound) -> Result<(), SendError> {
+        let shared = &self.0;
+        let mut items = shared.items.lock().unwrap();
+        if items.closed {
+            return Err(SendError);
+        }
+
+        if items.queue.len() >= shared.capacity {
+            // control events are never dropped, a queue with nothing else in it is as stuck as a full one
+            let droppable = match shared.policy {
+                SlowConsumerPolicy::DropOldest => items.queue.iter().position(|queued| !queued.control),
+                SlowConsumerPolicy::Disconnect => None,
+            };
+            match droppable {
+                Some(index) => {
+                    items.queue.remove(index);
+                    shared.stats.dropped_events.fetch_add(1, Ordering::Relaxed);
+                }
+                None => {
+                    shared.stats.dropped_events.fetch_add(items.queue.len() as u64 + 1, Ordering::Relaxed);
+                    shared.stats.evicted_connections.fetch_add(1, Ordering::Relaxed);
+                    items.queue.clear();
+                    items.closed = true;
+                    shared.evicted.store(true, Ordering::Release);
+                    drop(items);
+
+                    shared.available.notify_one();
+                    shared.eviction.notify_one();
+                    return Err(SendError);
+                }
+            }
+        }
+
+        items.queue.push_back(outbound);
+        drop(items);
+        shared.available.notify_one();
+        Ok(())
+    }
+}
+
+pub struct QueueReceiver(Arc<Shared>);
+
+impl QueueReceiver {
+    // None once the queue is closed, check is_evicted to tell why
+    pub async fn recv(&mut self) -> Option<Outbound> {
+        loop {
+            {
+                let mut items = self.0.items.lock().unwrap();
+                if let Some(outbound) = items.queue.pop_front() {
+                    return Some(outbound);
+                }
+                if items.closed {
+                    return None;
+                }
+            }
+            self.0.available.notified().await;
+        }
+    }
+
+    pub fn is_evicted(&self) -> bool {
+        self.0.evicted.load(Ordering::Acquire)
+    }
+
+    // resolves once the connection has been evicted for falling behind
+    pub async fn evicted(&self) {
+        while !self.is_evicted() {
+            self.0.eviction.notified().await;
+        }
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        let mut items = self.0.items.lock().unwrap();
+        items.closed = true;
+        items.queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::ws::Message;
+
+    use super::*;
+
+    fn outbound(frame: &str, control: bool) -> Outbound {
+        Outbound {
+            message_id: None,
+            deleted_group: None,
+            removed_member: None,
+            control,
+            frame: Message::Text(frame.to_string()),
+        }
+    }
+
+    fn queue(policy: SlowConsumerPolicy) -> (QueueSender, QueueReceiver, Arc<QueueStats>) {
+        let stats = Arc::new(QueueStats::default());
+        let (tx, rx) = channel(QueueConfig { capacity: 3, policy }, stats.clone());
+        (tx, rx, stats)
+    }
+
+    async fn frames(rx: &mut QueueReceiver, count: usize) -> Vec<String> {
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            match rx.recv().await.map(|outbound| outbound.frame) {
+                Some(Message::Text(frame)) => frames.push(frame),
+                _ => panic!("expected a queued text frame"),
+            }
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_control_events() {
+        let (tx, mut rx, stats) = queue(SlowConsumerPolicy::DropOldest);
+        tx.send(outbound("deleted", true)).unwrap();
+        tx.send(outbound("a", false)).unwrap();
+        tx.send(outbound("b", false)).unwrap();
+        tx.send(outbound("c", false)).unwrap();
+        tx.send(outbound("removed", true)).unwrap();
+
+        assert_eq!(frames(&mut rx, 3).await, ["deleted", "c", "removed"]);
+        assert_eq!(stats.dropped_events.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.evicted_connections.load(Ordering::Relaxed), 0);
+        assert!(!rx.is_evicted());
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_evicts_a_queue_of_control_events() {
+        let (tx, mut rx, stats) = queue(SlowConsumerPolicy::DropOldest);
+        for _ in 0..3 {
+            tx.send(outbound("deleted", true)).unwrap();
+        }
+
+        assert!(tx.send(outbound("a", false)).is_err());
+        assert!(rx.is_evicted());
+        assert!(rx.recv().await.is_none());
+        assert_eq!(stats.dropped_events.load(Ordering::Relaxed), 4);
+        assert_eq!(stats.evicted_connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_on_overflow() {
+        let (tx, mut rx, stats) = queue(SlowConsumerPolicy::Disconnect);
+        for frame in ["a", "b", "c"] {
+            tx.send(outbound(frame, false)).unwrap();
+        }
+
+        assert!(tx.send(outbound("d", false)).is_err());
+        rx.evicted().await;
+        assert!(rx.recv().await.is_none());
+        // nothing gets through once it's closed
+        assert!(tx.send(outbound("e", false)).is_err());
+        assert_eq!(stats.dropped_events.load(Ordering::Relaxed), 4);
+        assert_eq!(stats.evicted_connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn delivers_in_order_below_capacity() {
+        let (tx, mut rx, stats) = queue(SlowConsumerPolicy::Disconnect);
+        tx.send(outbound("a", false)).unwrap();
+        tx.send(outbound("b", true)).unwrap();
+        assert_eq!(frames(&mut rx, 2).await, ["a", "b"]);
+        tx.send(outbound("c", false)).unwrap();
+        assert_eq!(frames(&mut rx, 1).await, ["c"]);
+
+        assert_eq!(stats.dropped_events.load(Ordering::Relaxed), 0);
+        assert!(!rx.is_evicted());
+    }
+}
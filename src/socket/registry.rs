@@ -0,0 +1,95 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+
+use uuid::Uuid;
+
+use crate::socket::queue::QueueSender;
+use crate::utils::types::Outbound;
+
+const SHARD_COUNT: usize = 64;
+
+type Shard = HashMap<i32, HashMap<Uuid, QueueSender>>;
+
+// The groups a connection subscribed to, kept by the connection so leaving only touches their shards.
+// Can still list a group the connection was unsubscribed from by a removal, unsubscribing again is harmless
+pub type JoinedGroups = Arc<Mutex<HashSet<i32>>>;
+
+// Connections subscribed to each group, split into shards by group id so joins, leaves and
+// broadcasts in different groups don't wait on each other. Sending only pushes onto the
+// connection's queue, so no lock is ever held across an await
+pub struct ChannelRegistry {
+    shards: Vec<RwLock<Shard>>,
+}
+
+impl Default for ChannelRegistry {
+    fn default() -> Self {
+        ChannelRegistry { shards: (0..SHARD_COUNT).map(|_| RwLock::default()).collect() }
+    }
+}
+
+impl ChannelRegistry {
+    fn shard(&self, group_id: i32) -> &RwLock<Shard> {
+        &self.shards[group_id as u32 as usize % self.shards.len()]
+    }
+
+    pub fn subscribe(&self, group_id: i32, connection_id: Uuid, tx: QueueSender) {
+        let mut shard = self.shard(group_id).write().unwrap();
+        shard.entry(group_id).or_default().insert(connection_id, tx);
+    }
+
+    pub fn unsubscribe(&self, group_id: i32, connection_id: Uuid) {
+        let mut shard = self.shard(group_id).write().unwrap();
+        if let Some(channel) = shard.get_mut(&group_id) {
+            channel.remove(&connection_id);
+
+            if channel.is_empty() {
+                shard.remove(&group_id);
+            }
+        }
+    }
+
+    pub fn is_subscribed(&self, group_id: i32, connection_id: Uuid) -> bool {
+        let shard = self.shard(group_id).read().unwrap();
+        shard.get(&group_id).is_some_and(|channel| channel.contains_key(&connection_id))
+    }
+
+    pub fn remove_group(&self, group_id: i32) {
+        self.shard(group_id).write().unwrap().remove(&group_id);
+    }
+
+    // drops the connection from the groups it joined, only their shards are locked
+    pub fn remove_connection(&self, connection_id: Uuid, group_ids: impl IntoIterator<Item = i32>) {
+        for group_id in group_ids {
+            self.unsubscribe(group_id, connection_id);
+        }
+    }
+
+    pub fn broadcast(&self, group_id: i32, skip: Option<Uuid>, outbound: &Outbound) {
+        let shard = self.shard(group_id).read().unwrap();
+
+        if let Some(channel) = shard.get(&group_id) {
+            for (peer_connection_id, peer_tx) in channel.iter() {
+                if skip == Some(*peer_connection_id) {
+                    continue;
+                }
+                if peer_tx.send(outbound.clone()).is_err() {
+                    eprintln!(
+                        "Failed to send message to {}",
+                        peer_connection_id
+                    );
+                }
+            }
+        }
+    }
+
+    // adds every connection in the group to recipients, for events that go out to several groups at once
+    pub fn collect_connections(&self, group_id: i32, recipients: &mut HashMap<Uuid, QueueSender>) {
+        let shard = self.shard(group_id).read().unwrap();
+
+        if let Some(channel) = shard.get(&group_id) {
+            for (connection_id, tx) in channel.iter() {
+                recipients.insert(*connection_id, tx.clone());
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{watch, Notify};
+
+// Graceful shutdown of the open sockets. Once it begins, new sockets are turned away and open ones are
+// closed after the frame they're handling, the server waits on `drained` before exiting
+pub struct Shutdown {
+    closing: watch::Sender<bool>,
+    connections: AtomicUsize,
+    drained: Notify,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown { closing: watch::channel(false).0, connections: AtomicUsize::new(0), drained: Notify::new() }
+    }
+}
+
+impl Shutdown {
+    pub fn begin(&self) {
+        self.closing.send_replace(true);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        *self.closing.borrow()
+    }
+
+    // resolves once shutdown has begun
+    pub async fn closing(&self) {
+        let mut closing = self.closing.subscribe();
+        // the sender lives as long as self, so this can't fail
+        let _ = closing.wait_for(|closing| *closing).await;
+    }
+
+    // keeps the server from exiting until the returned guard is dropped
+    pub fn track(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::AcqRel);
+        ConnectionGuard(self.clone())
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.connections.load(Ordering::Acquire)
+    }
+
+    // resolves once every tracked connection has finished
+    pub async fn drained(&self) {
+        loop {
+            let drained = self.drained.notified();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+
+            if self.open_connections() == 0 {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+pub struct ConnectionGuard(Arc<Shutdown>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.0.connections.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
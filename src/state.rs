@@ -1,5 +1,4 @@
 use sqlx::PgPool;
-use axum::extract::ws::Message;
 use std::{
     collections::HashMap,
     sync::Arc,
@@ -7,10 +6,37 @@ use std::{
 use tokio::sync::{mpsc, Mutex};
 use uuid::Uuid;
 
-pub type ChannelMap = HashMap<i32, HashMap<Uuid, mpsc::UnboundedSender<Message>>>;
+use crate::socket::fanout::Fanout;
+use crate::socket::heartbeat::HeartbeatConfig;
+use crate::socket::limits::{MessageLimits, RateLimiter};
+use crate::socket::presence::PresenceUpdate;
+use crate::socket::queue::{QueueConfig, QueueSender, QueueStats};
+use crate::socket::registry::{ChannelRegistry, JoinedGroups};
+use crate::socket::shutdown::Shutdown;
+use crate::storage::Storage;
+
+pub struct UserConnection {
+    pub tx: QueueSender,
+    pub idle: bool,
+    // the groups the connection is subscribed to, so they can be rechecked after missed events
+    pub joined: JoinedGroups,
+}
+
+// live connections per user, a user without an entry is offline
+pub type UserMap = HashMap<i32, HashMap<Uuid, UserConnection>>;
 
 #[derive(Clone)]
 pub struct ServerState {
     pub db: PgPool,
-    pub channels: Arc<Mutex<ChannelMap>>
-}
\ No newline at end of file
+    pub channels: Arc<ChannelRegistry>,
+    pub users: Arc<Mutex<UserMap>>,
+    pub presence_updates: mpsc::UnboundedSender<PresenceUpdate>,
+    pub queue_config: QueueConfig,
+    pub queue_stats: Arc<QueueStats>,
+    pub fanout: Arc<Fanout>,
+    pub heartbeat: HeartbeatConfig,
+    pub shutdown: Arc<Shutdown>,
+    pub message_limits: MessageLimits,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub storage: Arc<dyn Storage>,
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::state::ServerState;
+use crate::storage::Storage;
+use crate::utils::queries::delete_unsent_attachments;
+
+// how long an upload can wait to be sent before it's removed
+const UNSENT_ATTACHMENT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const EXPIRY_BATCH_SIZE: i64 = 500;
+
+// Removes uploads that were never sent, rows first so nothing points at a file that's gone
+pub async fn expire_unsent(state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.closing() => return,
+        }
+
+        loop {
+            let keys = match delete_unsent_attachments(UNSENT_ATTACHMENT_TTL.as_secs_f64(), EXPIRY_BATCH_SIZE, &state.db).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    eprintln!("Failed to expire unsent attachments: {}", e);
+                    break;
+                }
+            };
+            delete_files(state.storage.as_ref(), &keys).await;
+            if (keys.len() as i64) < EXPIRY_BATCH_SIZE {
+                break;
+            }
+        }
+    }
+}
+
+// Removes the files of attachments whose rows are already deleted, a file that fails to go is only logged
+pub async fn delete_files(storage: &dyn Storage, keys: &[String]) {
+    for key in keys.iter() {
+        if let Err(e) = storage.delete(key).await {
+            eprintln!("Failed to remove attachment {}: {}", key, e);
+        }
+    }
+}
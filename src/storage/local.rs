@@ -0,0 +1,59 @@
+use std::io;
+use std::path::PathBuf;
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use uuid::Uuid;
+
+use crate::storage::Storage;
+
+// Stores every file as its key in a single directory
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&root)?;
+        Ok(LocalStorage { root })
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        // keys are plain names, anything that could leave the directory is a bug
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') || key.starts_with('.') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid storage key {}", key)));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put<'a>(&'a self, key: &'a str, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            let path = self.path(key)?;
+            // written next to it first so a crash never leaves a half written file under the key
+            let partial = self.root.join(format!(".{}.partial", Uuid::new_v4()));
+            tokio::fs::write(&partial, data).await?;
+            if let Err(e) = tokio::fs::rename(&partial, &path).await {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(e);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        async move { tokio::fs::read(self.path(key)?).await }.boxed()
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            match tokio::fs::remove_file(self.path(key)?).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        }
+        .boxed()
+    }
+}
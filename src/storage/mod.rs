@@ -0,0 +1,26 @@
+use std::io;
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+
+pub mod attachment;
+pub mod local;
+pub mod picture;
+
+// Where uploaded files are kept, keys are generated by the server and never come from a client
+pub trait Storage: Send + Sync {
+    fn put<'a>(&'a self, key: &'a str, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>>;
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>>;
+}
+
+// STORAGE_BACKEND picks the implementation, only `local` (the default) exists so far and keeps files under STORAGE_PATH
+pub fn from_env() -> Arc<dyn Storage> {
+    match std::env::var("STORAGE_BACKEND").as_deref() {
+        Err(_) | Ok("local") => {
+            let root = std::env::var("STORAGE_PATH").unwrap_or_else(|_| "uploads".to_string());
+            Arc::new(local::LocalStorage::new(root.into()).expect("Failed to create STORAGE_PATH"))
+        }
+        Ok(_) => panic!("STORAGE_BACKEND must be local"),
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use uuid::Uuid;
+
+use crate::storage::Storage;
+
+// largest upload accepted for a profile or group picture, in bytes
+pub const MAX_PICTURE_SIZE: usize = 10 * 1024 * 1024;
+// square thumbnails kept for every picture, the first one is the default
+pub const PICTURE_SIZES: [u32; 2] = [256, 64];
+const MAX_DIMENSION: u32 = 8192;
+// decoding memory cap, so a small file that claims to be a huge image can't take the server down
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+
+pub enum PictureError {
+    NotAnImage,
+    Storage(std::io::Error),
+}
+
+pub fn picture_key(id: Uuid, size: u32) -> String {
+    format!("picture-{}-{}.png", id, size)
+}
+
+// PUBLIC_URL is where clients reach this server. It ends up in every saved picture URL, so there's no default
+// to silently fall back to, the server refuses to start without it
+pub fn check_public_url() {
+    let public_url = std::env::var("PUBLIC_URL").expect("PUBLIC_URL must be set");
+    match url::Url::parse(&public_url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => panic!("PUBLIC_URL must be an http or https URL"),
+    }
+}
+
+// The URL saved in users.profile_picture and groups.profile_picture
+pub fn picture_url(id: Uuid) -> String {
+    let public_url = std::env::var("PUBLIC_URL").expect("PUBLIC_URL must be set");
+    format!("{}/picture/get?id={}", public_url.trim_end_matches('/'), id)
+}
+
+// the id of a picture stored here, None for URLs saved before uploads existed
+pub fn picture_id_from_url(url: &str) -> Option<Uuid> {
+    let (_, id) = url.split_once("/picture/get?id=")?;
+    Uuid::parse_str(id).ok()
+}
+
+// Decodes the upload and stores a PNG thumbnail for each of PICTURE_SIZES. Re-encoding drops
+// every bit of metadata, the EXIF orientation is applied first so photos still show upright
+pub async fn store_picture(storage: &Arc<dyn Storage>, data: Vec<u8>) -> Result<Uuid, PictureError> {
+    let thumbnails = tokio::task::spawn_blocking(move || make_thumbnails(&data))
+        .await
+        .expect("thumbnail task panicked")
+        .ok_or(PictureError::NotAnImage)?;
+
+    let id = Uuid::new_v4();
+    for (size, thumbnail) in PICTURE_SIZES.iter().zip(thumbnails.iter()) {
+        if let Err(e) = storage.put(&picture_key(id, *size), thumbnail).await {
+            delete_picture(storage, id).await;
+            return Err(PictureError::Storage(e));
+        }
+    }
+    Ok(id)
+}
+
+pub async fn delete_picture(storage: &Arc<dyn Storage>, id: Uuid) {
+    for size in PICTURE_SIZES {
+        if let Err(e) = storage.delete(&picture_key(id, size)).await {
+            eprintln!("Failed to remove picture {}: {}", id, e);
+        }
+    }
+}
+
+fn make_thumbnails(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let reader = ImageReader::new(Cursor::new(data)).with_guessed_format().ok()?;
+    if !matches!(reader.format(), Some(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP)) {
+        return None;
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+
+    let mut decoder = reader.into_decoder().ok()?;
+    decoder.set_limits(limits).ok()?;
+    let orientation = decoder.orientation().ok()?;
+    let mut image = DynamicImage::from_decoder(decoder).ok()?;
+    image.apply_orientation(orientation);
+
+    PICTURE_SIZES
+        .iter()
+        .map(|size| {
+            let mut png = Vec::new();
+            image
+                .resize_to_fill(*size, *size, FilterType::Lanczos3)
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .ok()?;
+            Some(png)
+        })
+        .collect()
+}
@@ -0,0 +1,61 @@
+use sqlx::PgPool;
+
+use crate::utils::types::{NewAttachment, StoredAttachment};
+
+pub async fn insert_attachment(attachment: &NewAttachment<'_>, db: &PgPool) -> Result<i32, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO attachments (storage_key, group_id, uploader_id, filename, content_type, size, checksum)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id
+        "#,
+        attachment.storage_key,
+        attachment.group_id,
+        attachment.uploader_id,
+        attachment.filename,
+        attachment.content_type,
+        attachment.size,
+        attachment.checksum
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn fetch_attachment(attachment_id: i32, db: &PgPool) -> Result<StoredAttachment, sqlx::Error> {
+    sqlx::query_as!(
+        StoredAttachment,
+        r#"
+        SELECT a.id, a.storage_key, a.group_id, a.uploader_id, a.message_id, a.filename, a.content_type, a.checksum,
+            m.deleted_at IS NOT NULL AS "message_deleted!"
+        FROM attachments a
+        LEFT JOIN messages m ON a.message_id = m.id
+        WHERE a.id = $1
+        "#,
+        attachment_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+// removes uploads never sent within max_age_secs and returns their storage keys, skipping any being sent right now
+pub async fn delete_unsent_attachments(max_age_secs: f64, limit: i64, db: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        DELETE FROM attachments
+        WHERE id IN (
+            SELECT id
+            FROM attachments
+            WHERE message_id IS NULL
+                AND created_at < now() - make_interval(secs => $1)
+            ORDER BY created_at
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING storage_key
+        "#,
+        max_age_secs,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
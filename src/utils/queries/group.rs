@@ -1,26 +1,108 @@
-use sqlx::PgPool;
-use crate::utils::types::{Group, Friend, Message};
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use sqlx::types::Json;
+use crate::utils::types::{AttachmentInfo, GroupLeave, GroupMember, GroupOverview, GroupRole, GroupSettings, Message, MessageDeletion, MessageKind, MessageEdit, MessageHistory, MessagePage, MessageRef, Reaction};
 
-pub async fn fetch_groups_for_user(user_id: i32, db: &PgPool) -> Result<Vec<Group>, sqlx::Error> {
+pub async fn fetch_group_ids_for_user(user_id: i32, db: &PgPool) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT group_id
+        FROM group_members
+        WHERE user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_all(db).await
+}
+
+// the groups out of group_ids the user no longer belongs to, or that are gone. Temp chats have no members to check
+pub async fn fetch_lost_group_ids(user_id: i32, group_ids: &[i32], db: &PgPool) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT u.id AS "id!"
+        FROM UNNEST($2::INT[]) AS u(id)
+        WHERE NOT EXISTS (SELECT 1 FROM group_members WHERE group_id = u.id AND user_id = $1)
+            AND NOT EXISTS (SELECT 1 FROM groups WHERE id = u.id AND group_type = 3)
+        "#,
+        user_id,
+        group_ids
+    )
+    .fetch_all(db).await
+}
+
+// like fetch_group_ids_for_user but without temp chats, whose channels anyone with the password can join
+pub async fn fetch_private_group_ids_for_user(user_id: i32, db: &PgPool) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT gm.group_id
+        FROM group_members gm
+        JOIN groups g ON g.id = gm.group_id
+        WHERE gm.user_id = $1 AND g.group_type != 3
+        "#,
+        user_id
+    )
+    .fetch_all(db).await
+}
+
+pub async fn fetch_group_overviews_for_user(user_id: i32, db: &PgPool) -> Result<Vec<GroupOverview>, sqlx::Error> {
     sqlx::query_as!(
-        Group,
+        GroupOverview,
         r#"
-        SELECT g.id, g.name, g.profile_picture, g.group_type
+        SELECT g.id, g.name, g.profile_picture, g.group_type, gm.last_read_message_id,
+            (
+                SELECT COUNT(*)
+                FROM messages m
+                WHERE m.group_id = g.id
+                AND m.id > COALESCE(gm.last_read_message_id, 0)
+                AND m.user_id != $1
+                AND m.deleted_at IS NULL
+                AND m.kind = 'user'
+            ) AS "unread_count!",
+            lm.id AS "last_message_id?", lm.user_id AS "last_message_user_id?", lu.username AS "last_message_username?",
+            CASE WHEN lm.deleted_at IS NOT NULL THEN 'message deleted' ELSE LEFT(lm.content, 100) END AS "last_message_preview?",
+            lm.timestamp AS "last_message_timestamp?", lm.deleted_at IS NOT NULL AS "last_message_deleted?"
         FROM groups g
         JOIN group_members gm ON g.id = gm.group_id
+        LEFT JOIN LATERAL (
+            SELECT m.id, m.user_id, m.content, m.timestamp, m.deleted_at
+            FROM messages m
+            WHERE m.group_id = g.id
+            ORDER BY m.timestamp DESC, m.id DESC
+            LIMIT 1
+        ) lm ON TRUE
+        LEFT JOIN users lu ON lm.user_id = lu.id
         WHERE gm.user_id = $1
+        ORDER BY lm.timestamp DESC NULLS LAST, g.id
         "#,
         user_id
     )
     .fetch_all(db).await
 }
 
+// only moves forward, returns None when the member already read past message_id
+pub async fn mark_read(user_id: i32, group_id: i32, message_id: i32, db: &PgPool) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        UPDATE group_members gm
+        SET last_read_message_id = m.id
+        FROM messages m
+        WHERE gm.user_id = $1 AND gm.group_id = $2
+        AND m.id = $3 AND m.group_id = $2
+        AND (gm.last_read_message_id IS NULL OR gm.last_read_message_id < m.id)
+        RETURNING m.id
+        "#,
+        user_id,
+        group_id,
+        message_id
+    )
+    .fetch_optional(db).await
+}
+
 
-pub async fn fetch_group_members(group_id: i32, db: &PgPool) -> Result<Vec<Friend>, sqlx::Error>{
+pub async fn fetch_group_members(group_id: i32, db: &PgPool) -> Result<Vec<GroupMember>, sqlx::Error>{
     sqlx::query_as!(
-        Friend,
+        GroupMember,
         r#"
-        SELECT u.id, u.username, u.profile_picture
+        SELECT u.id, u.username, u.profile_picture, gm.role AS "role: GroupRole"
         FROM users u
         JOIN group_members gm ON u.id = gm.user_id
         WHERE gm.group_id = $1
@@ -29,14 +111,16 @@ pub async fn fetch_group_members(group_id: i32, db: &PgPool) -> Result<Vec<Frien
     ).fetch_all(db).await
 }
 
-pub async fn add_group_member(user_id: i32, group_id: i32, db: &PgPool) -> Result<(), sqlx::Error> {
+// new members start caught up, the history from before they joined doesn't count as unread
+pub async fn add_group_member(user_id: i32, group_id: i32, role: GroupRole, db: &PgPool) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"
-        INSERT INTO group_members (group_id, user_id)
-        VALUES ($1, $2)
+        INSERT INTO group_members (group_id, user_id, role, last_read_message_id)
+        VALUES ($1, $2, $3, (SELECT MAX(id) FROM messages WHERE group_id = $1))
         "#,
         group_id,
-        user_id
+        user_id,
+        role as GroupRole
     )
     .execute(db)
     .await?;
@@ -71,6 +155,74 @@ pub async fn remove_group_member(user_id: i32, group_id: i32, db: &PgPool) -> Re
     Ok(())
 }
 
+// Removes the member and, if they owned the group, hands it to the longest standing admin, or member
+// when there are no admins. Returns RowNotFound when the user isn't in the group
+pub async fn leave_group(user_id: i32, group_id: i32, db: &PgPool) -> Result<GroupLeave, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    // members leaving at the same time wait on each other, so the group can't end up without an owner
+    sqlx::query!(
+        r#"
+        SELECT id
+        FROM groups
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        group_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let role = sqlx::query_scalar!(
+        r#"
+        DELETE FROM group_members
+        WHERE group_id = $1 AND user_id = $2
+        RETURNING role AS "role: GroupRole"
+        "#,
+        group_id,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(sqlx::Error::RowNotFound)?;
+
+    let new_owner_id = if role == GroupRole::Owner {
+        sqlx::query_scalar!(
+            r#"
+            UPDATE group_members
+            SET role = 'owner'
+            WHERE group_id = $1 AND user_id = (
+                SELECT user_id
+                FROM group_members
+                WHERE group_id = $1
+                ORDER BY role DESC, joined_at, user_id
+                LIMIT 1
+            )
+            RETURNING user_id
+            "#,
+            group_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+    } else {
+        None
+    };
+
+    let remaining = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM group_members
+        WHERE group_id = $1
+        "#,
+        group_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(GroupLeave { remaining, new_owner_id })
+}
+
 pub async fn is_user_in_group(user_id: i32, group_id: i32, db: &PgPool) -> Result<(), sqlx::Error> {
     let members =  fetch_group_members(group_id, db).await?;
 
@@ -83,52 +235,512 @@ pub async fn is_user_in_group(user_id: i32, group_id: i32, db: &PgPool) -> Resul
         Err(sqlx::Error::RowNotFound)
 }
 
-pub async fn change_group_picture(group_id: i32, picture_url: String, db: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query!(
+// returns the picture it replaced
+pub async fn change_group_picture(group_id: i32, picture_url: String, db: &PgPool) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
         r#"
-        UPDATE groups
+        UPDATE groups g
         SET profile_picture = $1
-        WHERE id = $2
+        FROM (SELECT id, profile_picture FROM groups WHERE id = $2 FOR UPDATE) old
+        WHERE g.id = old.id
+        RETURNING old.profile_picture
         "#,
         picture_url,
         group_id
     )
-    .execute(db)
-    .await?;
-    Ok(())
+    .fetch_one(db)
+    .await
 }
 
-pub async fn fetch_messages(group_id: i32, db: &PgPool) -> Result<Vec<Message>, sqlx::Error> {
+// Pages backwards from `before` (or the latest message) unless `after` is set, then pages forwards.
+// viewer_id is used for `reacted_by_me` and is None for temp chats, which have no logged in viewer
+pub async fn fetch_messages(group_id: i32, viewer_id: Option<i32>, page: &MessagePage, db: &PgPool) -> Result<MessageHistory, sqlx::Error> {
+    let mut ids = sqlx::query_scalar!(
+        r#"
+        SELECT m.id
+        FROM messages m
+        WHERE m.group_id = $1
+        AND ($2::INT IS NULL OR (m.timestamp, m.id) < (SELECT c.timestamp, c.id FROM messages c WHERE c.id = $2))
+        AND ($3::INT IS NULL OR (m.timestamp, m.id) > (SELECT c.timestamp, c.id FROM messages c WHERE c.id = $3))
+        ORDER BY
+            CASE WHEN $3::INT IS NULL THEN m.timestamp END DESC,
+            CASE WHEN $3::INT IS NULL THEN m.id END DESC,
+            m.timestamp, m.id
+        LIMIT $4
+        "#,
+        group_id,
+        page.before,
+        page.after,
+        page.limit + 1
+    )
+    .fetch_all(db).await?;
+
+    // one extra row was fetched to know whether another page exists
+    let has_more = ids.len() as i64 > page.limit;
+    ids.truncate(page.limit as usize);
+    let next_cursor = if has_more { ids.last().copied() } else { None };
+
+    if page.after.is_none() {
+        ids.reverse();
+    }
+
+    let messages = fetch_messages_by_ids(&ids, viewer_id, db).await?;
+    Ok(MessageHistory { messages, next_cursor })
+}
+
+// The root message followed by every reply under it, in chronological order
+pub async fn fetch_thread(message_id: i32, viewer_id: i32, db: &PgPool) -> Result<Vec<Message>, sqlx::Error> {
+    let ids = sqlx::query_scalar!(
+        r#"
+        WITH RECURSIVE thread AS (
+            SELECT id FROM messages WHERE id = $1
+            UNION ALL
+            SELECT r.id FROM messages r JOIN thread t ON r.reply_to = t.id
+        )
+        SELECT m.id
+        FROM thread t
+        JOIN messages m ON t.id = m.id
+        ORDER BY m.timestamp, m.id
+        "#,
+        message_id
+    )
+    .fetch_all(db).await?;
+
+    fetch_messages_by_ids(&ids, Some(viewer_id), db).await
+}
+
+// Messages in any of the groups with an id greater than after_id, in id order, used to replay what a reconnecting socket missed
+pub async fn fetch_messages_since(group_ids: &[i32], after_id: i32, viewer_id: i32, limit: i64, db: &PgPool) -> Result<Vec<Message>, sqlx::Error> {
+    let ids = sqlx::query_scalar!(
+        r#"
+        SELECT m.id
+        FROM messages m
+        WHERE m.group_id = ANY($1) AND m.id > $2
+        ORDER BY m.id
+        LIMIT $3
+        "#,
+        group_ids,
+        after_id,
+        limit
+    )
+    .fetch_all(db).await?;
+
+    fetch_messages_by_ids(&ids, Some(viewer_id), db).await
+}
+
+// Where a replay after last_seen has to start. Ids are handed out when a message is stored but it only shows up
+// once its transaction commits, so a message with a lower id can appear after the client already got last_seen.
+// Anything stored within `overlap_secs` before last_seen is replayed again, the last 100 at most
+pub async fn fetch_replay_start(group_ids: &[i32], last_seen: i32, overlap_secs: f64, db: &PgPool) -> Result<i32, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT COALESCE(MIN(recent.id) - 1, $2) AS "start!"
+        FROM (
+            SELECT m.id, m.timestamp
+            FROM messages m
+            WHERE m.group_id = ANY($1) AND m.id <= $2
+            ORDER BY m.id DESC
+            LIMIT 100
+        ) recent
+        WHERE recent.timestamp >= (SELECT s.timestamp FROM messages s WHERE s.id = $2) - make_interval(secs => $3)
+        "#,
+        group_ids,
+        last_seen,
+        overlap_secs
+    )
+    .fetch_one(db).await
+}
+
+// The one place a Message is built, every query above picks the ids and this loads them in the same order.
+// Deleted messages keep their place but lose their content and attachments
+async fn fetch_messages_by_ids<'e, E>(ids: &[i32], viewer_id: Option<i32>, executor: E) -> Result<Vec<Message>, sqlx::Error>
+where
+    E: PgExecutor<'e>,
+{
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
     sqlx::query_as!(
         Message,
         r#"
-        SELECT m.id, m.content, m.user_id, m.timestamp, m.group_id, u.username, u.profile_picture 
+        SELECT m.id, m.user_id, m.timestamp, m.group_id, m.edited_at, u.username, u.profile_picture,
+            m.kind AS "kind: MessageKind", m.target_user_id,
+            CASE WHEN m.deleted_at IS NULL THEN m.content ELSE 'message deleted' END AS "content!",
+            m.deleted_at IS NOT NULL AS "deleted!",
+            m.reply_to,
+            CASE WHEN p.deleted_at IS NULL THEN LEFT(p.content, 100) ELSE 'message deleted' END AS "reply_preview?",
+            COALESCE((
+                SELECT json_agg(json_build_object('emoji', r.emoji, 'count', r.count, 'reacted_by_me', r.reacted_by_me) ORDER BY r.first_reacted_at)
+                FROM (
+                    SELECT emoji, COUNT(*) AS count, COALESCE(bool_or(user_id = $2::INT), FALSE) AS reacted_by_me, MIN(created_at) AS first_reacted_at
+                    FROM message_reactions
+                    WHERE message_id = m.id
+                    GROUP BY emoji
+                ) r
+            ), '[]') AS "reactions!: Json<Vec<Reaction>>",
+            CASE WHEN m.deleted_at IS NULL THEN COALESCE((
+                SELECT json_agg(json_build_object('id', a.id, 'filename', a.filename, 'content_type', a.content_type, 'size', a.size) ORDER BY a.id)
+                FROM attachments a
+                WHERE a.message_id = m.id
+            ), '[]') ELSE '[]' END AS "attachments!: Json<Vec<AttachmentInfo>>"
         FROM messages m
         JOIN users u ON m.user_id = u.id
-        WHERE m.group_id = $1
-        ORDER BY m.timestamp
+        LEFT JOIN messages p ON m.reply_to = p.id
+        WHERE m.id = ANY($1)
+        ORDER BY array_position($1, m.id)
         "#,
-        group_id
+        ids,
+        viewer_id
     )
-    .fetch_all(db).await
+    .fetch_all(executor).await
 }
 
-pub async fn insert_message_in_db(user_id: i32, group_id:i32, content: String, db: &PgPool) 
+// Stores the message and links the attachments to it, which have to be unsent uploads by the same user to the same group.
+// Nothing is stored and RowNotFound is returned if any of them isn't
+pub async fn insert_message_in_db(user_id: i32, group_id:i32, content: String, reply_to: Option<i32>, attachment_ids: &[i32], db: &PgPool) 
 -> Result<Message, sqlx::Error> {
-    sqlx::query_as!(
-        Message,
+    let mut tx = db.begin().await?;
+
+    let message_id = sqlx::query_scalar!(
         r#"
-        WITH inserted AS (
-          INSERT INTO messages (user_id, content, group_id)
-          VALUES ($1, $2, $3)
-          RETURNING id, user_id, content, timestamp, group_id
+        INSERT INTO messages (user_id, content, group_id, reply_to)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id
+        "#,
+        user_id,
+        content,
+        group_id,
+        reply_to
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if !attachment_ids.is_empty() {
+        let linked = sqlx::query!(
+            r#"
+            UPDATE attachments
+            SET message_id = $1
+            WHERE id = ANY($2) AND group_id = $3 AND uploader_id = $4 AND message_id IS NULL
+            "#,
+            message_id,
+            attachment_ids,
+            group_id,
+            user_id
         )
-        SELECT i.id, i.user_id, i.content, i.timestamp, i.group_id, u.username, u.profile_picture
-        FROM inserted i
-        JOIN users u ON i.user_id = u.id;
+        .execute(&mut *tx)
+        .await?;
+
+        // dropping the transaction rolls the message back
+        if linked.rows_affected() != attachment_ids.len() as u64 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+    }
+
+    let message = fetch_new_message(message_id, &mut tx).await?;
+    tx.commit().await?;
+    Ok(message)
+}
+
+// content is what clients that don't know the kind show
+pub async fn insert_system_message(group_id: i32, user_id: i32, kind: MessageKind, target_user_id: Option<i32>, content: String, db: &PgPool)
+-> Result<Message, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let message_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO messages (user_id, content, group_id, kind, target_user_id)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id
         "#,
         user_id,
         content,
+        group_id,
+        kind as MessageKind,
+        target_user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let message = fetch_new_message(message_id, &mut tx).await?;
+    tx.commit().await?;
+    Ok(message)
+}
+
+// a message that was just stored, read inside the transaction that stored it
+async fn fetch_new_message(message_id: i32, tx: &mut Transaction<'_, Postgres>) -> Result<Message, sqlx::Error> {
+    fetch_messages_by_ids(&[message_id], None, &mut **tx)
+        .await?
+        .pop()
+        .ok_or(sqlx::Error::RowNotFound)
+}
+
+pub async fn fetch_message_ref(message_id: i32, db: &PgPool) -> Result<MessageRef, sqlx::Error> {
+    sqlx::query_as!(
+        MessageRef,
+        r#"
+        SELECT user_id, group_id, deleted_at IS NOT NULL AS "deleted!", kind <> 'user' AS "system!"
+        FROM messages
+        WHERE id = $1
+        "#,
+        message_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+// Saves the current content as a revision before overwriting it, RowNotFound if the message was deleted meanwhile
+pub async fn edit_message(message_id: i32, content: String, db: &PgPool) -> Result<MessageEdit, sqlx::Error> {
+    sqlx::query_as!(
+        MessageEdit,
+        r#"
+        WITH previous AS (
+            SELECT id, content
+            FROM messages
+            WHERE id = $1 AND deleted_at IS NULL
+            FOR UPDATE
+        ), revision AS (
+            INSERT INTO message_revisions (message_id, content)
+            SELECT id, content FROM previous
+        )
+        UPDATE messages m
+        SET content = $2, edited_at = now()
+        FROM previous
+        WHERE m.id = previous.id
+        RETURNING m.id, m.group_id, m.content, m.edited_at AS "edited_at!"
+        "#,
+        message_id,
+        content
+    )
+    .fetch_one(db)
+    .await
+}
+
+// Clears the content, revisions and attachments but keeps the row so replies and history still line up
+pub async fn delete_message(message_id: i32, db: &PgPool) -> Result<MessageDeletion, sqlx::Error> {
+    sqlx::query_as!(
+        MessageDeletion,
+        r#"
+        WITH revisions AS (
+            DELETE FROM message_revisions
+            WHERE message_id = $1
+        ), removed AS (
+            DELETE FROM attachments
+            WHERE message_id = $1
+            RETURNING storage_key
+        )
+        UPDATE messages
+        SET content = '', deleted_at = now()
+        WHERE id = $1 AND deleted_at IS NULL
+        RETURNING id, group_id, ARRAY(SELECT storage_key FROM removed) AS "attachment_keys!"
+        "#,
+        message_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+// None when the user isn't in the group
+pub async fn fetch_member_role(user_id: i32, group_id: i32, db: &PgPool) -> Result<Option<GroupRole>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT role AS "role: GroupRole"
+        FROM group_members
+        WHERE user_id = $1 AND group_id = $2
+        "#,
+        user_id,
+        group_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+// The owner role only moves with transfer_group_ownership, returns RowNotFound when the user isn't a member
+pub async fn set_member_role(user_id: i32, group_id: i32, role: GroupRole, db: &PgPool) -> Result<(), sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE group_members
+        SET role = $3
+        WHERE user_id = $1 AND group_id = $2 AND role <> 'owner'
+        "#,
+        user_id,
+        group_id,
+        role as GroupRole
+    )
+    .execute(db)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(())
+}
+
+// The old owner stays on as an admin, returns RowNotFound when either of them isn't in the group
+pub async fn transfer_group_ownership(owner_id: i32, new_owner_id: i32, group_id: i32, db: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    // demoted first, a group can only have one owner at a time
+    let demoted = sqlx::query!(
+        r#"
+        UPDATE group_members
+        SET role = 'admin'
+        WHERE user_id = $1 AND group_id = $2 AND role = 'owner'
+        "#,
+        owner_id,
+        group_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let promoted = sqlx::query!(
+        r#"
+        UPDATE group_members
+        SET role = 'owner'
+        WHERE user_id = $1 AND group_id = $2
+        "#,
+        new_owner_id,
+        group_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if demoted.rows_affected() == 0 || promoted.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    tx.commit().await
+}
+
+// Authors can delete their own messages, group admins and temp chat creators can delete any message in the group
+pub async fn can_delete_message(user_id: i32, message: &MessageRef, db: &PgPool) -> Result<bool, sqlx::Error> {
+    if message.user_id == user_id {
+        return Ok(true);
+    }
+
+    sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM group_members
+            WHERE group_id = $1 AND user_id = $2 AND role >= 'admin'
+        ) OR EXISTS (
+            SELECT 1
+            FROM temp_groups_info
+            WHERE group_id = $1 AND user_id = $2
+        ) AS "allowed!"
+        "#,
+        message.group_id,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+pub async fn add_reaction(message_id: i32, user_id: i32, emoji: &str, db: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO message_reactions (message_id, user_id, emoji)
+        VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        "#,
+        message_id,
+        user_id,
+        emoji
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn remove_reaction(message_id: i32, user_id: i32, emoji: &str, db: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE
+        FROM message_reactions
+        WHERE message_id = $1 AND user_id = $2 AND emoji = $3
+        "#,
+        message_id,
+        user_id,
+        emoji
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn fetch_reaction_count(message_id: i32, emoji: &str, db: &PgPool) -> Result<i64, sqlx::Error> {
+    let count = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM message_reactions
+        WHERE message_id = $1 AND emoji = $2
+        "#,
+        message_id,
+        emoji
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(count)
+}
+
+pub async fn fetch_group_settings(group_id: i32, db: &PgPool) -> Result<GroupSettings, sqlx::Error> {
+    sqlx::query_as!(
+        GroupSettings,
+        r#"
+        SELECT id AS group_id, name, description, only_admins_can_post, only_admins_can_add
+        FROM groups
+        WHERE id = $1
+        "#,
+        group_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+// None leaves a setting as it is, an empty description removes it
+pub async fn update_group_settings(
+    group_id: i32,
+    name: Option<String>,
+    description: Option<String>,
+    only_admins_can_post: Option<bool>,
+    only_admins_can_add: Option<bool>,
+    db: &PgPool,
+) -> Result<GroupSettings, sqlx::Error> {
+    sqlx::query_as!(
+        GroupSettings,
+        r#"
+        UPDATE groups
+        SET name = COALESCE($2, name),
+            description = CASE WHEN $3::TEXT IS NULL THEN description ELSE NULLIF($3, '') END,
+            only_admins_can_post = COALESCE($4, only_admins_can_post),
+            only_admins_can_add = COALESCE($5, only_admins_can_add)
+        WHERE id = $1
+        RETURNING id AS group_id, name, description, only_admins_can_post, only_admins_can_add
+        "#,
+        group_id,
+        name,
+        description,
+        only_admins_can_post,
+        only_admins_can_add
+    )
+    .fetch_one(db)
+    .await
+}
+
+// false when the group only lets admins post and the user isn't one
+pub async fn can_post_in_group(user_id: i32, group_id: i32, db: &PgPool) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT NOT g.only_admins_can_post OR EXISTS (
+            SELECT 1
+            FROM group_members
+            WHERE group_id = g.id AND user_id = $1 AND role >= 'admin'
+        ) AS "allowed!"
+        FROM groups g
+        WHERE g.id = $2
+        "#,
+        user_id,
         group_id
     )
     .fetch_one(db)
@@ -150,17 +762,23 @@ pub async fn fetch_group_type(group_id: i32, db: &PgPool) -> Result<i32, sqlx::E
     Ok(result.group_type)
 }
 
-pub async fn delete_group(group_id: i32, db: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query!(
+// returns the storage keys of the attachments deleted along with it, whose files are left for the caller to remove
+pub async fn delete_group(group_id: i32, db: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
         r#"
-        DELETE FROM groups
-        WHERE id = $1
+        WITH deleted AS (
+            DELETE FROM groups
+            WHERE id = $1
+            RETURNING id
+        )
+        SELECT a.storage_key
+        FROM attachments a
+        JOIN deleted d ON d.id = a.group_id
         "#,
         group_id
     )
-    .execute(db)
-    .await?;
-    Ok(())
+    .fetch_all(db)
+    .await
 }
 
 pub async fn fetch_dm_id(user_id: i32, friend_id: i32, db: &PgPool) -> Result<i32, sqlx::Error> {
@@ -9,3 +9,15 @@ pub use temp_group::*;
 
 pub mod user;
 pub use user::*;
+
+pub mod relay;
+pub use relay::*;
+
+pub mod search;
+pub use search::*;
+
+pub mod attachment;
+pub use attachment::*;
+
+pub mod presence;
+pub use presence::*;
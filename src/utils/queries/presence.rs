@@ -0,0 +1,114 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::utils::types::{Presence, UserPresence};
+
+// true when the instance had no row, the first time or after another instance took it for dead
+pub async fn touch_instance(instance_id: Uuid, db: &PgPool) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO server_instances (id)
+        VALUES ($1)
+        ON CONFLICT (id) DO UPDATE SET last_seen = now()
+        RETURNING (xmax = 0) AS "inserted!"
+        "#,
+        instance_id
+    )
+    .fetch_one(db).await
+}
+
+pub async fn count_live_peers(instance_id: Uuid, timeout_secs: f64, db: &PgPool) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM server_instances
+        WHERE id <> $1
+            AND last_seen > now() - make_interval(secs => $2)
+        "#,
+        instance_id,
+        timeout_secs
+    )
+    .fetch_one(db).await
+}
+
+// drops the given instance, or every instance that missed its heartbeats when there is none,
+// returning the best status each of their users had on them
+pub async fn remove_instances(instance_id: Option<Uuid>, timeout_secs: f64, db: &PgPool) -> Result<Vec<UserPresence>, sqlx::Error> {
+    // the select still sees the presence rows the delete cascades to
+    sqlx::query_as!(
+        UserPresence,
+        r#"
+        WITH removed AS (
+            DELETE FROM server_instances
+            WHERE id = $1
+                OR ($1 IS NULL AND last_seen < now() - make_interval(secs => $2))
+            RETURNING id
+        )
+        SELECT user_id, MAX(status) AS "status!: Presence"
+        FROM instance_presence
+        WHERE instance_id IN (SELECT id FROM removed)
+        GROUP BY user_id
+        "#,
+        instance_id,
+        timeout_secs
+    )
+    .fetch_all(db).await
+}
+
+pub async fn set_instance_presence(instance_id: Uuid, user_id: i32, status: Presence, db: &PgPool) -> Result<(), sqlx::Error> {
+    if status == Presence::Offline {
+        sqlx::query!(
+            r#"
+            DELETE FROM instance_presence
+            WHERE instance_id = $1 AND user_id = $2
+            "#,
+            instance_id,
+            user_id
+        )
+        .execute(db)
+        .await?;
+        return Ok(());
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO instance_presence (instance_id, user_id, status)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (instance_id, user_id) DO UPDATE SET status = EXCLUDED.status
+        "#,
+        instance_id,
+        user_id,
+        status as Presence
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+// the user's best status on the live instances, leaving one out when given
+pub async fn fetch_presence_statuses(
+    user_ids: &[i32],
+    except_instance: Option<Uuid>,
+    timeout_secs: f64,
+    db: &PgPool,
+) -> Result<Vec<UserPresence>, sqlx::Error> {
+    sqlx::query_as!(
+        UserPresence,
+        r#"
+        SELECT u.id AS "user_id!", COALESCE(MAX(p.status), 'offline') AS "status!: Presence"
+        FROM UNNEST($1::INT[]) AS u(id)
+        LEFT JOIN instance_presence p
+            ON p.user_id = u.id
+            AND p.instance_id IS DISTINCT FROM $2
+            AND p.instance_id IN (
+                SELECT id FROM server_instances
+                WHERE last_seen > now() - make_interval(secs => $3)
+            )
+        GROUP BY u.id
+        "#,
+        user_ids,
+        except_instance,
+        timeout_secs
+    )
+    .fetch_all(db).await
+}
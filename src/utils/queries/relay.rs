@@ -0,0 +1,66 @@
+use sqlx::PgPool;
+
+pub async fn notify(channel: &str, payload: &str, db: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        SELECT pg_notify($1, $2)
+        "#,
+        channel,
+        payload
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn store_relayed_event(payload: &str, db: &PgPool) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO relayed_events (payload)
+        VALUES ($1)
+        RETURNING id
+        "#,
+        payload
+    )
+    .fetch_one(db).await
+}
+
+pub async fn fetch_relayed_event(id: i64, db: &PgPool) -> Result<String, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT payload
+        FROM relayed_events
+        WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_one(db).await
+}
+
+// every instance has had plenty of time to read them by then
+pub async fn prune_relayed_events(db: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM relayed_events
+        WHERE created_at < now() - INTERVAL '5 minutes'
+        "#
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+// one round-trip for the lot, postgres delivers them in order
+pub async fn notify_all(channel: &str, payloads: &[String], db: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        SELECT pg_notify($1, payload)
+        FROM UNNEST($2::TEXT[]) AS payload
+        "#,
+        channel,
+        payloads
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use sqlx::PgPool;
+
+use crate::utils::types::{SearchQuery, SearchResult};
+
+// Matches in the non temp groups user_id is a member of, plus temp_group_id once its credentials were checked.
+// One extra row is fetched so the caller can tell whether there's another page
+pub async fn search_messages(
+    user_id: Option<i32>,
+    temp_group_id: Option<i32>,
+    query: &SearchQuery,
+    db: &PgPool,
+) -> Result<Vec<SearchResult>, sqlx::Error> {
+    sqlx::query_as!(
+        SearchResult,
+        r#"
+        SELECT m.id, m.group_id, g.name AS group_name, m.user_id, u.username, u.profile_picture, m.content, m.timestamp,
+            ts_headline(
+                'english',
+                replace(replace(replace(m.content, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'),
+                q,
+                'StartSel=<mark>, StopSel=</mark>, MaxWords=30, MinWords=10, MaxFragments=2'
+            ) AS "snippet!"
+        FROM messages m
+        JOIN groups g ON m.group_id = g.id
+        JOIN users u ON m.user_id = u.id
+        CROSS JOIN websearch_to_tsquery('english', $1) q
+        WHERE m.search_vector @@ q
+        AND m.deleted_at IS NULL
+        AND m.kind = 'user'
+        AND (
+            m.group_id = $3
+            OR (g.group_type <> 3 AND EXISTS (
+                SELECT 1 FROM group_members gm WHERE gm.group_id = m.group_id AND gm.user_id = $2
+            ))
+        )
+        AND ($4::INT IS NULL OR m.group_id = $4)
+        AND ($5::INT IS NULL OR m.user_id = $5)
+        AND ($6::TIMESTAMPTZ IS NULL OR m.timestamp >= $6)
+        AND ($7::TIMESTAMPTZ IS NULL OR m.timestamp < $7)
+        AND ($8::INT IS NULL OR (m.timestamp, m.id) < (SELECT c.timestamp, c.id FROM messages c WHERE c.id = $8))
+        ORDER BY m.timestamp DESC, m.id DESC
+        LIMIT $9
+        "#,
+        query.text,
+        user_id,
+        temp_group_id,
+        query.group_id,
+        query.user_id,
+        query.from,
+        query.to,
+        query.before,
+        query.limit + 1
+    )
+    .fetch_all(db)
+    .await
+}
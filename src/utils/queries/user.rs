@@ -15,7 +15,7 @@ pub async fn fetch_stats(user_id: i32, db: &PgPool) -> Result<String, sqlx::Erro
         r#"
         SELECT COUNT(*)
         FROM messages
-        WHERE user_id = $1
+        WHERE user_id = $1 AND kind = 'user'
         "#,
         user_id
     ).fetch_one(db).await?;
@@ -25,7 +25,7 @@ pub async fn fetch_stats(user_id: i32, db: &PgPool) -> Result<String, sqlx::Erro
         SELECT g.name
         FROM groups g
         JOIN messages m ON g.id = m.group_id
-        WHERE m.user_id = $1 AND g.group_type = 1
+        WHERE m.user_id = $1 AND g.group_type = 1 AND m.kind = 'user'
         GROUP BY g.name
         ORDER BY COUNT(m.id) DESC LIMIT 1;
         "#,
@@ -42,7 +42,7 @@ pub async fn fetch_stats(user_id: i32, db: &PgPool) -> Result<String, sqlx::Erro
             FROM groups g
             JOIN messages m ON g.id = m.group_id
             WHERE g.group_type = 2 
-            AND m.user_id = $1
+            AND m.user_id = $1 AND m.kind = 'user'
             GROUP BY g.id
             ORDER BY COUNT(m.id) DESC
             LIMIT 1
@@ -57,7 +57,7 @@ pub async fn fetch_stats(user_id: i32, db: &PgPool) -> Result<String, sqlx::Erro
         SELECT m.content
         FROM messages m
         JOIN groups g ON m.group_id = g.id
-        WHERE m.user_id = $1 AND g.group_type != 3
+        WHERE m.user_id = $1 AND g.group_type != 3 AND m.kind = 'user'
         ORDER BY LENGTH(m.content) DESC
         LIMIT 1;
         "#,
@@ -79,4 +79,54 @@ pub async fn fetch_stats(user_id: i32, db: &PgPool) -> Result<String, sqlx::Erro
     let json_output = serde_json::to_string_pretty(&stats).map_err(|_| sqlx::Error::RowNotFound);
     
     return json_output;
-}
\ No newline at end of file
+}
+pub async fn fetch_username(user_id: i32, db: &PgPool) -> Result<String, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT username
+        FROM users
+        WHERE id = $1
+        "#,
+        user_id
+    ).fetch_one(db).await
+}
+
+// returns the picture it replaced
+pub async fn change_user_picture(user_id: i32, picture_url: String, db: &PgPool) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        UPDATE users u
+        SET profile_picture = $1
+        FROM (SELECT id, profile_picture FROM users WHERE id = $2 FOR UPDATE) old
+        WHERE u.id = old.id
+        RETURNING old.profile_picture
+        "#,
+        picture_url,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+// The users out of user_ids whose presence the viewer may see: themselves, their friends and anyone
+// they share a group or DM with. Temp chats don't count, anyone with the password can join those
+pub async fn fetch_visible_user_ids(viewer_id: i32, user_ids: &[i32], db: &PgPool) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT u.id AS "id!"
+        FROM UNNEST($2::INT[]) AS u(id)
+        WHERE u.id = $1
+        OR EXISTS (SELECT 1 FROM friendships f WHERE f.user_id = $1 AND f.friend_id = u.id)
+        OR EXISTS (
+            SELECT 1
+            FROM group_members mine
+            JOIN group_members theirs ON theirs.group_id = mine.group_id
+            JOIN groups g ON g.id = mine.group_id
+            WHERE mine.user_id = $1 AND theirs.user_id = u.id AND g.group_type != 3
+        )
+        "#,
+        viewer_id,
+        user_ids
+    )
+    .fetch_all(db).await
+}
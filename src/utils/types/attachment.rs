@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+pub const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+
+// Anything else is rejected on upload. SVG is left out since it can carry scripts
+const ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "image/png", "image/jpeg", "image/gif", "image/webp",
+    "video/mp4", "video/webm", "audio/mpeg", "audio/ogg", "audio/webm",
+    "application/pdf", "application/zip", "text/plain",
+];
+
+// Attachment as listed on a message
+#[derive(Serialize, Deserialize)]
+pub struct AttachmentInfo {
+    pub id: i32,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+}
+
+pub struct NewAttachment<'a> {
+    pub storage_key: &'a str,
+    pub group_id: i32,
+    pub uploader_id: i32,
+    pub filename: &'a str,
+    pub content_type: &'a str,
+    pub size: i64,
+    pub checksum: &'a str,
+}
+
+pub struct StoredAttachment {
+    pub id: i32,
+    pub storage_key: String,
+    pub group_id: i32,
+    pub uploader_id: Option<i32>,
+    pub message_id: Option<i32>,
+    pub message_deleted: bool,
+    pub filename: String,
+    pub content_type: String,
+    pub checksum: String,
+}
+
+pub fn is_allowed_content_type(content_type: &str) -> bool {
+    ALLOWED_CONTENT_TYPES.contains(&content_type)
+}
+
+// images and media open in the browser, everything else downloads
+pub fn is_inline_content_type(content_type: &str) -> bool {
+    ["image/", "video/", "audio/"].iter().any(|prefix| content_type.starts_with(prefix))
+}
@@ -0,0 +1,304 @@
+use axum::extract::ws::Message as WsMessage;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::utils::types::{GroupSettings, Message, MessageDeletion, MessageEdit, ReactionChange, ReadReceipt, RoleChange, UserPresence};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+fn default_version() -> u8 {
+    PROTOCOL_VERSION
+}
+
+// Envelope for every frame a client sends, e.g. {"v":1,"type":"send","content":"hi"}
+#[derive(Deserialize)]
+pub struct ClientFrame {
+    #[serde(default = "default_version")]
+    pub v: u8,
+    #[serde(flatten)]
+    pub event: ClientEvent,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    // group_id may be omitted on /ws/group/:group_id sockets
+    Send {
+        group_id: Option<i32>,
+        content: String,
+        // id of the message being replied to, must be in the same group
+        reply_to: Option<i32>,
+        // uploaded with /attachment/upload to the same group, the content may be empty when there are any
+        #[serde(default)]
+        attachment_ids: Vec<i32>,
+        // echoed back in the ack so the client can match it to its pending message
+        nonce: Option<String>,
+    },
+    // clients resend typing_start every few seconds while typing, it expires on its own otherwise
+    #[serde(alias = "typing")]
+    TypingStart {
+        group_id: Option<i32>,
+    },
+    TypingStop {
+        group_id: Option<i32>,
+    },
+    // marks everything up to and including message_id as read
+    Read {
+        group_id: Option<i32>,
+        message_id: i32,
+    },
+    // a user shows as idle once every one of their connections is idle
+    Idle,
+    Active,
+    Subscribe {
+        group_id: i32,
+        // only needed for temp chats
+        password: Option<String>,
+    },
+    Unsubscribe {
+        group_id: i32,
+    },
+    Edit {
+        message_id: i32,
+        content: String,
+    },
+    Delete {
+        message_id: i32,
+    },
+    React {
+        message_id: i32,
+        emoji: String,
+    },
+    Unreact {
+        message_id: i32,
+        emoji: String,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Ready {
+        group_ids: Vec<i32>,
+    },
+    // sent after the missed messages of a resumed connection, live events follow
+    Resumed {
+        replayed: usize,
+        last_message_id: Option<i32>,
+    },
+    // sent instead of resumed when too much was missed, the messages after last_message_id have to be fetched over HTTP
+    ResumeTruncated {
+        replayed: usize,
+        last_message_id: Option<i32>,
+    },
+    Subscribed {
+        group_id: i32,
+    },
+    Unsubscribed {
+        group_id: i32,
+    },
+    // the group is gone, connections are unsubscribed and /ws/group/:group_id sockets closed
+    GroupDeleted {
+        group_id: i32,
+    },
+    // the user's connections are unsubscribed from the group and their /ws/group/:group_id sockets closed
+    MemberRemoved(RemovedMember),
+    Message(Message),
+    MessageEdited(MessageEdit),
+    MessageDeleted(MessageDeletion),
+    ReactionAdded(ReactionChange),
+    ReactionRemoved(ReactionChange),
+    Ack {
+        group_id: i32,
+        nonce: Option<String>,
+        message_id: i32,
+    },
+    TypingStart(Typing),
+    TypingStop(Typing),
+    Presence(UserPresence),
+    ReadReceipt(ReadReceipt),
+    RoleChanged(RoleChange),
+    GroupUpdated {
+        updated_by: i32,
+        #[serde(flatten)]
+        settings: GroupSettings,
+    },
+    Error {
+        code: ErrorCode,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<i32>,
+        message: String,
+        // set with rate_limited, when the client may try again
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+}
+
+#[derive(Serialize, Clone)]
+pub struct Typing {
+    pub group_id: i32,
+    pub user_id: i32,
+    pub username: String,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MalformedFrame,
+    UnsupportedVersion,
+    UnsupportedFrame,
+    MissingGroup,
+    NotSubscribed,
+    Forbidden,
+    NotFound,
+    InvalidContent,
+    RateLimited,
+    InternalError,
+}
+
+// A serialized event queued for a connection, message_id is set for new messages
+// so a resuming socket can skip the ones it already got from the replay
+#[derive(Clone)]
+pub struct Outbound {
+    pub message_id: Option<i32>,
+    pub deleted_group: Option<i32>,
+    pub removed_member: Option<RemovedMember>,
+    // never dropped for a slow consumer, see ServerEvent::is_control
+    pub control: bool,
+    pub frame: WsMessage,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct RemovedMember {
+    pub group_id: i32,
+    pub user_id: i32,
+}
+
+#[derive(Serialize)]
+struct ServerFrame<'a> {
+    v: u8,
+    #[serde(flatten)]
+    event: &'a ServerEvent,
+}
+
+impl ServerEvent {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerEvent::Error { code, group_id: None, message: message.into(), retry_after_ms: None }
+    }
+
+    pub fn group_error(code: ErrorCode, group_id: i32, message: impl Into<String>) -> Self {
+        ServerEvent::Error { code, group_id: Some(group_id), message: message.into(), retry_after_ms: None }
+    }
+
+    pub fn rate_limited(group_id: i32, message: impl Into<String>, retry_after: Duration) -> Self {
+        ServerEvent::Error {
+            code: ErrorCode::RateLimited,
+            group_id: Some(group_id),
+            message: message.into(),
+            retry_after_ms: Some(retry_after.as_millis() as u64),
+        }
+    }
+
+    pub fn to_message(&self) -> WsMessage {
+        WsMessage::Text(self.to_text())
+    }
+
+    pub fn to_text(&self) -> String {
+        let frame = ServerFrame { v: PROTOCOL_VERSION, event: self };
+        serde_json::to_string(&frame).expect("server events always serialize")
+    }
+
+    pub fn message_id(&self) -> Option<i32> {
+        match self {
+            ServerEvent::Message(message) => Some(message.id),
+            _ => None,
+        }
+    }
+
+    pub fn deleted_group(&self) -> Option<i32> {
+        match self {
+            ServerEvent::GroupDeleted { group_id } => Some(*group_id),
+            _ => None,
+        }
+    }
+
+    pub fn removed_member(&self) -> Option<RemovedMember> {
+        match self {
+            ServerEvent::MemberRemoved(removed) => Some(*removed),
+            _ => None,
+        }
+    }
+
+    // changes to what the client may see or do in a group, losing one leaves the client wrong until it reconnects
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            ServerEvent::GroupDeleted { .. } | ServerEvent::MemberRemoved(_) | ServerEvent::RoleChanged(_) | ServerEvent::GroupUpdated { .. }
+        )
+    }
+
+    // only matter for a few seconds, fine to drop rather than queue behind anything
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, ServerEvent::TypingStart(_) | ServerEvent::TypingStop(_))
+    }
+
+    pub fn to_outbound(&self) -> Outbound {
+        Outbound {
+            message_id: self.message_id(),
+            deleted_group: self.deleted_group(),
+            removed_member: self.removed_member(),
+            control: self.is_control(),
+            frame: self.to_message(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn parse(frame: Value) -> Result<ClientFrame, serde_json::Error> {
+        serde_json::from_value(frame)
+    }
+
+    #[test]
+    fn version_defaults_to_the_current_one() {
+        let frame = parse(json!({ "type": "send", "content": "hi" })).unwrap();
+        assert_eq!(frame.v, PROTOCOL_VERSION);
+        assert!(matches!(frame.event, ClientEvent::Send { group_id: None, ref content, .. } if content == "hi"));
+
+        let frame = parse(json!({ "v": 2, "type": "idle" })).unwrap();
+        assert_eq!(frame.v, 2);
+    }
+
+    #[test]
+    fn unknown_types_are_malformed() {
+        assert!(parse(json!({ "v": 1, "type": "shout", "content": "hi" })).is_err());
+        assert!(parse(json!({ "v": 1, "content": "hi" })).is_err());
+        assert!(parse(json!({ "v": 1, "type": "read", "group_id": 1 })).is_err());
+    }
+
+    #[test]
+    fn typing_is_an_alias_for_typing_start() {
+        let frame = parse(json!({ "type": "typing", "group_id": 4 })).unwrap();
+        assert!(matches!(frame.event, ClientEvent::TypingStart { group_id: Some(4) }));
+        let frame = parse(json!({ "type": "typing_start" })).unwrap();
+        assert!(matches!(frame.event, ClientEvent::TypingStart { group_id: None }));
+    }
+
+    #[test]
+    fn server_frames_carry_the_version_and_type() {
+        let frame: Value = serde_json::from_str(&ServerEvent::Subscribed { group_id: 4 }.to_text()).unwrap();
+        assert_eq!(frame, json!({ "v": 1, "type": "subscribed", "group_id": 4 }));
+
+        let removed = ServerEvent::MemberRemoved(RemovedMember { group_id: 4, user_id: 7 });
+        let frame: Value = serde_json::from_str(&removed.to_text()).unwrap();
+        assert_eq!(frame, json!({ "v": 1, "type": "member_removed", "group_id": 4, "user_id": 7 }));
+
+        let error = ServerEvent::error(ErrorCode::MalformedFrame, "bad");
+        let frame: Value = serde_json::from_str(&error.to_text()).unwrap();
+        assert_eq!(frame, json!({ "v": 1, "type": "error", "code": "malformed_frame", "message": "bad" }));
+    }
+}
@@ -1,23 +1,295 @@
-use serde::Deserialize;
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::types::Json;
 use chrono::{DateTime, Utc};
 
-pub struct Group {
+use crate::utils::types::AttachmentInfo;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+pub const MAX_PAGE_SIZE: i64 = 100;
+// in characters, after trimming
+pub const MAX_GROUP_NAME_LENGTH: usize = 100;
+pub const MAX_GROUP_DESCRIPTION_LENGTH: usize = 1000;
+
+// Ordered by what the role is allowed to do. Admins manage members and the group picture,
+// the owner also manages admins. Only regular groups have an owner
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[sqlx(type_name = "group_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum GroupRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+// What a message is, anything but User is posted by the server when the group changes
+#[derive(sqlx::Type, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[sqlx(type_name = "message_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    User,
+    MemberAdded,
+    MemberRemoved,
+    MemberLeft,
+    GroupRenamed,
+    DescriptionChanged,
+    PictureChanged,
+    SettingsChanged,
+    RoleChanged,
+}
+
+// A change to announce in the group, the actor is whoever made it
+pub enum SystemMessage<'a> {
+    MemberAdded { target_id: i32 },
+    MemberRemoved { target_id: i32 },
+    MemberLeft,
+    GroupRenamed { name: &'a str },
+    DescriptionChanged,
+    PictureChanged,
+    SettingsChanged,
+    RoleChanged { target_id: i32, role: GroupRole },
+}
+
+impl SystemMessage<'_> {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            SystemMessage::MemberAdded { .. } => MessageKind::MemberAdded,
+            SystemMessage::MemberRemoved { .. } => MessageKind::MemberRemoved,
+            SystemMessage::MemberLeft => MessageKind::MemberLeft,
+            SystemMessage::GroupRenamed { .. } => MessageKind::GroupRenamed,
+            SystemMessage::DescriptionChanged => MessageKind::DescriptionChanged,
+            SystemMessage::PictureChanged => MessageKind::PictureChanged,
+            SystemMessage::SettingsChanged => MessageKind::SettingsChanged,
+            SystemMessage::RoleChanged { .. } => MessageKind::RoleChanged,
+        }
+    }
+
+    pub fn target_id(&self) -> Option<i32> {
+        match self {
+            SystemMessage::MemberAdded { target_id }
+            | SystemMessage::MemberRemoved { target_id }
+            | SystemMessage::RoleChanged { target_id, .. } => Some(*target_id),
+            _ => None,
+        }
+    }
+
+    // the text stored as the content, target is the target's username
+    pub fn describe(&self, actor: &str, target: &str) -> String {
+        match self {
+            SystemMessage::MemberAdded { .. } => format!("{} added {}", actor, target),
+            SystemMessage::MemberRemoved { .. } => format!("{} removed {}", actor, target),
+            SystemMessage::MemberLeft => format!("{} left the group", actor),
+            SystemMessage::GroupRenamed { name } => format!("{} renamed the group to {}", actor, name),
+            SystemMessage::DescriptionChanged => format!("{} changed the group description", actor),
+            SystemMessage::PictureChanged => format!("{} changed the group picture", actor),
+            SystemMessage::SettingsChanged => format!("{} changed the group settings", actor),
+            SystemMessage::RoleChanged { role: GroupRole::Owner, .. } => {
+                format!("{} made {} the group owner", actor, target)
+            }
+            SystemMessage::RoleChanged { role: GroupRole::Admin, .. } => format!("{} made {} an admin", actor, target),
+            SystemMessage::RoleChanged { role: GroupRole::Member, .. } => {
+                format!("{} made {} a regular member", actor, target)
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GroupMember {
+    pub id: i32,
+    pub username: String,
+    pub profile_picture: Option<String>,
+    pub role: GroupRole,
+}
+
+// Group as listed for a member, with their unread count and the latest message
+pub struct GroupOverview {
     pub id: i32,
     pub name: String,
     pub profile_picture: Option<String>,
     pub group_type: i32,
+    pub last_read_message_id: Option<i32>,
+    pub unread_count: i64,
+    pub last_message_id: Option<i32>,
+    pub last_message_user_id: Option<i32>,
+    pub last_message_username: Option<String>,
+    // first 100 characters of the latest message
+    pub last_message_preview: Option<String>,
+    pub last_message_timestamp: Option<DateTime<Utc>>,
+    pub last_message_deleted: Option<bool>,
 }
 
+#[derive(Serialize)]
 pub struct Message {
     pub id: i32,
     pub content: String,
     pub user_id: i32,
     pub username: String,
+    #[serde(serialize_with = "serialize_timestamp")]
     pub timestamp: DateTime<Utc>,
     pub profile_picture: Option<String>,
     pub group_id: i32,
+    #[serde(serialize_with = "serialize_optional_timestamp")]
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted: bool,
+    pub reply_to: Option<i32>,
+    // first 100 characters of the parent message
+    pub reply_preview: Option<String>,
+    pub reactions: Json<Vec<Reaction>>,
+    pub attachments: Json<Vec<AttachmentInfo>>,
+    pub kind: MessageKind,
+    // who a system message is about, like the member that was added
+    pub target_user_id: Option<i32>,
+}
+
+// Reactions on a message aggregated per emoji
+#[derive(Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: i64,
+    pub reacted_by_me: bool,
+}
+
+#[derive(Serialize)]
+pub struct ReactionChange {
+    pub message_id: i32,
+    pub group_id: i32,
+    pub user_id: i32,
+    pub emoji: String,
+    // number of users with this reaction after the change
+    pub count: i64,
+}
+
+pub struct GroupLeave {
+    // members still in the group, it should be deleted once this is 0
+    pub remaining: i64,
+    // set when the owner left and someone took over
+    pub new_owner_id: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct GroupSettings {
+    pub group_id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub only_admins_can_post: bool,
+    pub only_admins_can_add: bool,
+}
+
+#[derive(Serialize)]
+pub struct RoleChange {
+    pub group_id: i32,
+    pub user_id: i32,
+    pub role: GroupRole,
+}
+
+#[derive(Serialize)]
+pub struct ReadReceipt {
+    pub group_id: i32,
+    pub user_id: i32,
+    pub message_id: i32,
+}
+
+// Just enough of a message to check who may act on it
+pub struct MessageRef {
+    pub user_id: i32,
+    pub group_id: i32,
+    pub deleted: bool,
+    // system messages can't be edited or deleted
+    pub system: bool,
+}
+
+#[derive(Serialize)]
+pub struct MessageEdit {
+    pub id: i32,
+    pub group_id: i32,
+    pub content: String,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub edited_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct MessageDeletion {
+    pub id: i32,
+    pub group_id: i32,
+    // storage keys of the attachments removed with it, their files are deleted once the row is gone
+    #[serde(skip)]
+    pub attachment_keys: Vec<String>,
+}
+
+// Cursors are message ids, messages are ordered by (timestamp, id)
+pub struct MessagePage {
+    pub before: Option<i32>,
+    pub after: Option<i32>,
+    pub limit: i64,
+}
+
+impl MessagePage {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<MessagePage, &'static str> {
+        let parse_cursor = |key: &str| match params.get(key) {
+            Some(value) => value.parse::<i32>().map(Some).map_err(|_| "Invalid cursor"),
+            None => Ok(None),
+        };
+
+        let limit = match params.get("limit") {
+            Some(limit) => limit.parse::<i64>().map_err(|_| "Invalid limit")?,
+            None => DEFAULT_PAGE_SIZE,
+        };
+
+        Ok(MessagePage {
+            before: parse_cursor("before")?,
+            after: parse_cursor("after")?,
+            limit: limit.clamp(1, MAX_PAGE_SIZE),
+        })
+    }
+}
+
+// One page of messages in chronological order, next_cursor is None once there is nothing left
+pub struct MessageHistory {
+    pub messages: Vec<Message>,
+    pub next_cursor: Option<i32>,
+}
+
+// A single emoji from the Unicode list, skin tones and ZWJ sequences included. Returns its fully qualified
+// form so "❤" and "❤️" count as the same reaction
+pub fn check_emoji(emoji: &str) -> Result<&'static str, String> {
+    match emojis::get(emoji) {
+        Some(emoji) => Ok(emoji.as_str()),
+        None => Err("Invalid emoji".to_string()),
+    }
+}
+
+pub fn check_group_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Group name cannot be empty".to_string());
+    }
+    if name.chars().count() > MAX_GROUP_NAME_LENGTH {
+        return Err(format!("Group name cannot be longer than {} characters", MAX_GROUP_NAME_LENGTH));
+    }
+    Ok(name.to_string())
+}
+
+// an empty description is allowed, it removes the current one
+pub fn check_group_description(description: &str) -> Result<String, String> {
+    let description = description.trim();
+    if description.chars().count() > MAX_GROUP_DESCRIPTION_LENGTH {
+        return Err(format!("Description cannot be longer than {} characters", MAX_GROUP_DESCRIPTION_LENGTH));
+    }
+    Ok(description.to_string())
 }
 
+// keeps the same rfc3339 format the REST endpoints return
+pub(super) fn serialize_timestamp<S: Serializer>(timestamp: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&timestamp.to_rfc3339())
+}
+
+fn serialize_optional_timestamp<S: Serializer>(timestamp: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match timestamp {
+        Some(timestamp) => serialize_timestamp(timestamp, serializer),
+        None => serializer.serialize_none(),
+    }
+}
 
 #[derive(Deserialize)]
 pub struct CreateGroupForm {
@@ -47,10 +319,74 @@ pub struct RemoveUserForm {
 }
 
 #[derive(Deserialize)]
-pub struct EditPictureForm {
+pub struct LeaveGroupForm {
+    pub token: String,
+    #[serde(rename = "groupId")]
+    pub group_id: i32,
+}
+
+// Fields that are left out stay the same, an empty description removes it
+#[derive(Deserialize)]
+pub struct EditSettingsForm {
+    pub token: String,
+    #[serde(rename = "groupId")]
+    pub group_id: i32,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    #[serde(rename = "onlyAdminsCanPost")]
+    pub only_admins_can_post: Option<bool>,
+    #[serde(rename = "onlyAdminsCanAdd")]
+    pub only_admins_can_add: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleForm {
+    pub token: String,
+    #[serde(rename = "groupId")]
+    pub group_id: i32,
+    #[serde(rename = "userId")]
+    pub user_id: i32,
+    pub role: GroupRole,
+}
+
+#[derive(Deserialize)]
+pub struct TransferOwnershipForm {
     pub token: String,
     #[serde(rename = "groupId")]
     pub group_id: i32,
-    #[serde(rename = "pictureUrl")]
-    pub picture_url: String,
-}
\ No newline at end of file
+    #[serde(rename = "userId")]
+    pub user_id: i32,
+}
+
+
+#[derive(Deserialize)]
+pub struct EditMessageForm {
+    pub token: String,
+    #[serde(rename = "messageId")]
+    pub message_id: i32,
+    pub content: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteMessageForm {
+    pub token: String,
+    #[serde(rename = "messageId")]
+    pub message_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct ReactionForm {
+    pub token: String,
+    #[serde(rename = "messageId")]
+    pub message_id: i32,
+    pub emoji: String,
+}
+
+#[derive(Deserialize)]
+pub struct MarkReadForm {
+    pub token: String,
+    #[serde(rename = "groupId")]
+    pub group_id: i32,
+    #[serde(rename = "messageId")]
+    pub message_id: i32,
+}
@@ -9,3 +9,12 @@ pub use group::*;
 
 mod temp_group;
 pub use temp_group::*;
+
+mod event;
+pub use event::*;
+
+mod search;
+pub use search::*;
+
+mod attachment;
+pub use attachment::*;
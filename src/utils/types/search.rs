@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use serde::Serialize;
+use chrono::{DateTime, Utc};
+
+use super::group::serialize_timestamp;
+use crate::utils::types::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+
+// Filters for /search, newest matches first. `before` is the next_cursor of the previous page
+pub struct SearchQuery {
+    pub text: String,
+    pub group_id: Option<i32>,
+    pub user_id: Option<i32>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub before: Option<i32>,
+    pub limit: i64,
+}
+
+impl SearchQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<SearchQuery, &'static str> {
+        let text = match params.get("q").map(|q| q.trim()) {
+            Some(q) if !q.is_empty() => q.to_string(),
+            _ => return Err("Missing search query"),
+        };
+        let parse_id = |key: &str, error: &'static str| match params.get(key) {
+            Some(value) => value.parse::<i32>().map(Some).map_err(|_| error),
+            None => Ok(None),
+        };
+        let parse_date = |key: &str| match params.get(key) {
+            Some(value) => DateTime::parse_from_rfc3339(value)
+                .map(|date| Some(date.with_timezone(&Utc)))
+                .map_err(|_| "Invalid date, expected RFC 3339"),
+            None => Ok(None),
+        };
+
+        let limit = match params.get("limit") {
+            Some(limit) => limit.parse::<i64>().map_err(|_| "Invalid limit")?,
+            None => DEFAULT_PAGE_SIZE,
+        };
+
+        Ok(SearchQuery {
+            text,
+            group_id: parse_id("group_id", "Invalid group_id")?,
+            user_id: parse_id("user_id", "Invalid user_id")?,
+            from: parse_date("from")?,
+            to: parse_date("to")?,
+            before: parse_id("before", "Invalid cursor")?,
+            limit: limit.clamp(1, MAX_PAGE_SIZE),
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    pub id: i32,
+    pub group_id: i32,
+    pub group_name: String,
+    pub user_id: i32,
+    pub username: String,
+    pub profile_picture: Option<String>,
+    pub content: String,
+    // html escaped content around the matches, which are wrapped in <mark></mark>
+    pub snippet: String,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub timestamp: DateTime<Utc>,
+}
@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 pub struct RegisterForm {
@@ -7,8 +7,6 @@ pub struct RegisterForm {
     pub password: String,
     #[serde(rename = "confirmPassword")]
     pub confirm_password: String,
-    #[serde(rename = "profilePicture")]
-    pub profile_picture: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -16,3 +14,19 @@ pub struct LoginForm {
     pub username: String,
     pub password: String,
 }
+
+// ordered like the presence_status enum, so the best status across instances is the max
+#[derive(sqlx::Type, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[sqlx(type_name = "presence_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    Offline,
+    Idle,
+    Online,
+}
+
+#[derive(Serialize)]
+pub struct UserPresence {
+    pub user_id: i32,
+    pub status: Presence,
+}